@@ -1,6 +1,17 @@
-{lib, ...}: pkgs: let
-  inherit (builtins) deepSeq filter listToAttrs map parseDrvName seq tryEval;
-  inherit (lib) filterAttrs flatten foldl isDerivation mapAttrsToList optional optionals removePrefix traceVal;
+{
+  lib,
+  # how many levels of package sets with `recurseForDerivations` to index
+  depth ? 1,
+  # package sets to index even without `recurseForDerivations`, e.g. "python312Packages"
+  includeScopes ? [],
+  # package sets to leave out even with `recurseForDerivations`, e.g. "haskellPackages"
+  excludeScopes ? [],
+  # the system to check availability on, when `pkgs` doesn't say, e.g. for flake outputs
+  system ? null,
+  ...
+}: pkgs: let
+  inherit (builtins) concatStringsSep deepSeq elem filter isAttrs length listToAttrs map parseDrvName tryEval;
+  inherit (lib) attrByPath filterAttrs flatten isDerivation mapAttrsToList optionals removePrefix splitString;
 
   registerPackage = name: value: let
     safeValue = tryEval value;
@@ -76,74 +87,78 @@
         );
 
       meta = {
+        broken = safeVal.meta.broken or false;
         description = safeVal.meta.description or null;
         homepage = safeVal.meta.homepage or null;
+        insecure = safeVal.meta.insecure or false;
         license = safeVal.meta.license or null;
         longDescription = safeVal.meta.longDescription or null;
+        unfree = safeVal.meta.unfree or false;
+        unsupported = safeVal.meta.unsupported or false;
       };
     };
 
-    platformForAvailability = {system = pkgs.system or builtins.currentSystem;};
+    platformForAvailability = {
+      system =
+        if system != null
+        then system
+        else pkgs.system or builtins.currentSystem;
+    };
     isAvailableOn = tryEval (lib.meta.availableOn platformForAvailability safeValue.value);
-    available = safeValue.success && isDerivation value && isAvailableOn.success && isAvailableOn.value;
-
-    checkRegistryCondition = prev: {
-      reason,
-      ok,
-    }: let
-      isOk =
-        if !ok
-        then seq (traceVal "${name}: ${reason}") false
-        else true;
-    in
-      # change to `prev && isOk` to debug why a value isn't included
-      prev && ok;
 
-    shouldBeInRegistry = foldl checkRegistryCondition true [
-      {
-        reason = "not available on ${platformForAvailability.system}";
-        ok = available;
-      }
-      {
-        reason = "failed eval";
-        ok = safeRegistryValue.success;
-      }
-      {
-        reason = "no pname";
-        ok = safeRegistryValue.value.pname != null;
-      }
-    ];
-  in
-    optional shouldBeInRegistry {
-      inherit name;
-      value = let
+    # why the value is left out of the registry, or null if it isn't
+    exclusionReason =
+      if !safeValue.success
+      then "failed eval"
+      else if !isDerivation value
+      then "not a derivation"
+      else if !(isAvailableOn.success && isAvailableOn.value)
+      then "not available on ${platformForAvailability.system}"
+      else if !safeRegistryValue.success
+      then "failed to evaluate package information"
+      else if safeRegistryValue.value.pname == null
+      then "no pname"
+      else null;
+  in {
+    inherit name;
+    value =
+      if exclusionReason != null
+      then {excluded = exclusionReason;}
+      else let
         filtered-toplevel-attrs = filterAttrs (_: v: v != null) safeRegistryValue.value;
         filtered-meta-attrs = filterAttrs (_: v: v != null) safeRegistryValue.value.meta;
       in
         filtered-toplevel-attrs // {meta = filtered-meta-attrs;};
-    };
+  };
 
-  registerScope = scope-name: scope: let
-    safeScope = tryEval scope;
+  # the registry entries for the attributes of `set`, which is at `path` and `level` package sets
+  # deep, and for the package sets in it
+  registerSet = path: level: set: let
+    packages = mapAttrsToList (name: registerPackage (concatStringsSep "." (path ++ [name]))) set;
+    scopes = mapAttrsToList (name: registerScope (path ++ [name]) (level + 1)) set;
+  in
+    packages ++ flatten scopes;
 
-    list-of-scope-packages = mapAttrsToList registerPackage safeScope.value;
-    scope-registry-inner = flatten list-of-scope-packages;
-    scope-registry =
-      map
-      (item: {
-        name = "${scope-name}.${item.name}";
-        value = item.value;
-      })
-      scope-registry-inner;
+  registerScope = path: level: scope: let
+    safeScope = tryEval scope;
+    name = concatStringsSep "." path;
 
-    shouldBeInRegistry = safeScope.success && safeScope.value ? recurseForDerivations && safeScope.value.recurseForDerivations;
+    recurses = tryEval (safeScope.value ? recurseForDerivations && safeScope.value.recurseForDerivations);
+    shouldBeInRegistry =
+      safeScope.success
+      && isAttrs safeScope.value
+      && !(elem name excludeScopes)
+      && (elem name includeScopes || (level <= depth && recurses.success && recurses.value));
   in
-    optionals shouldBeInRegistry scope-registry;
+    optionals shouldBeInRegistry (registerSet path level safeScope.value);
 
-  list-of-registry-packages = mapAttrsToList registerPackage pkgs;
-  registry-items = flatten list-of-registry-packages;
+  # included package sets inside other package sets aren't reached if their parents aren't indexed
+  nested-scopes = flatten (map (name: let
+    path = splitString "." name;
+  in
+    optionals (length path > 1) (registerScope path (length path) (attrByPath path null pkgs)))
+  includeScopes);
 
-  scoped-registries = flatten (mapAttrsToList registerScope pkgs);
-  registry = listToAttrs (registry-items ++ scoped-registries);
+  registry = listToAttrs (registerSet [] 0 pkgs ++ nested-scopes);
 in
   registry
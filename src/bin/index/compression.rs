@@ -0,0 +1,221 @@
+use std::{
+    io::{self, BufRead, Read, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+
+/// How a file is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+impl Compression {
+    /// The compression matching the extension of `path`, like `registry.json.zst`.
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst" | "zstd") => Self::Zstd,
+            Some("br") => Self::Brotli,
+            _ => Self::None,
+        }
+    }
+
+    /// Detect the compression of a JSON file from its first bytes, or from the extension of `path`
+    /// for brotli, which has no magic bytes. Anything that can't be the start of a JSON document
+    /// is taken to be brotli too.
+    fn detect(start: &[u8], path: Option<&Path>) -> Self {
+        if start.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else if start.starts_with(ZSTD_MAGIC) {
+            Self::Zstd
+        } else if path.is_some_and(|path| Self::from_extension(path) == Self::Brotli)
+            || !could_be_json(start)
+        {
+            Self::Brotli
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Whether `start` can be the start of a JSON document, possibly after a byte order mark. Anything
+/// that isn't valid JSON is left for the JSON parser to report when it isn't brotli either.
+fn could_be_json(start: &[u8]) -> bool {
+    let start = start.strip_prefix(UTF8_BOM).unwrap_or(start);
+
+    match start.iter().find(|b| !b.is_ascii_whitespace()) {
+        None => true,
+        Some(b) => b"{[\"-0123456789tfn".contains(b),
+    }
+}
+
+/// Decompress `reader` if it holds gzip, zstd or brotli rather than plain JSON, skipping a UTF-8
+/// byte order mark in plain JSON. `path` is the file being read, if any.
+pub fn decompress<'r>(
+    mut reader: impl BufRead + 'r,
+    path: Option<&Path>,
+) -> io::Result<Box<dyn Read + 'r>> {
+    // magic bytes may be split across reads, e.g. from a pipe
+    let mut start = vec![0; ZSTD_MAGIC.len()];
+    let mut len = 0;
+    while len < start.len() {
+        match reader.read(&mut start[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    start.truncate(len);
+
+    let compression = Compression::detect(&start, path);
+    if compression == Compression::None && start.starts_with(UTF8_BOM) {
+        start.drain(..UTF8_BOM.len());
+    }
+    let reader = io::Cursor::new(start).chain(reader);
+
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Compression::Brotli => Box::new(brotli::Decompressor::new(reader, 64 * 1024)),
+    })
+}
+
+/// Compresses what is written to it, until [`Encoder::finish`] is called.
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Brotli(Box<brotli::CompressorWriter<W>>),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W, compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(writer),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Self::Zstd(zstd::Encoder::new(writer, 0)?),
+            Compression::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                writer,
+                64 * 1024,
+                9,
+                22,
+            ))),
+        })
+    }
+
+    /// Write the end of the compressed stream, and flush the underlying writer.
+    pub fn finish(self) -> io::Result<()> {
+        let mut writer = match self {
+            Self::None(writer) => writer,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+            Self::Brotli(encoder) => encoder.into_inner(),
+        };
+
+        writer.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read, Write};
+
+    use super::{Compression, Encoder};
+
+    #[test]
+    fn round_trip() {
+        let json = br#"{"hello": {"pname": "hello"}}"#;
+
+        for compression in [
+            Compression::None,
+            Compression::Gzip,
+            Compression::Zstd,
+            Compression::Brotli,
+        ] {
+            let mut compressed = vec![];
+            let mut encoder = Encoder::new(&mut compressed, compression).unwrap();
+            encoder.write_all(json).unwrap();
+            encoder.finish().unwrap();
+
+            let mut decompressed = vec![];
+            super::decompress(compressed.as_slice(), None)
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, json, "{compression:?}");
+        }
+    }
+
+    /// Reads one byte at a time, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn detect_short_reads() {
+        let json = br#"{"hello": {}}"#;
+
+        let mut compressed = vec![];
+        let mut encoder = Encoder::new(&mut compressed, Compression::Zstd).unwrap();
+        encoder.write_all(json).unwrap();
+        encoder.finish().unwrap();
+
+        let with_bom = [b"\xef\xbb\xbf".as_slice(), json].concat();
+        for input in [compressed.as_slice(), &with_bom, b"", b"  "] {
+            let mut decompressed = vec![];
+            let reader = BufReader::with_capacity(1, Trickle(input));
+            super::decompress(reader, None)
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+
+            let expected = if input.trim_ascii().is_empty() {
+                input
+            } else {
+                json
+            };
+            assert_eq!(decompressed, expected);
+        }
+    }
+}
@@ -1,32 +1,106 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub type Registry = HashMap<String, PackageInfo>;
 
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PackageInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub pname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub meta: Option<PackageMeta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub store_paths: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub propagated_build_inputs: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub propagated_native_build_inputs: Option<Vec<String>>,
+    /// Store paths for each system the package is available on. Only present in registries
+    /// evaluated for explicit systems, see [`PackageInfo::set_system`] and [`merge_systems`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub systems: Option<BTreeMap<String, HashMap<String, String>>>,
+    /// Why `genRegistry` left the attribute out of the index. Excluded entries have no other
+    /// information.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excluded: Option<String>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+impl PackageInfo {
+    /// Record the store paths of a package evaluated for `system` as its store paths on that
+    /// system. Excluded entries are left as they are.
+    pub fn set_system(&mut self, system: &str) {
+        if self.excluded.is_none() {
+            let store_paths = self.store_paths.clone().unwrap_or_default();
+            self.systems = Some(BTreeMap::from([(system.to_string(), store_paths)]));
+        }
+    }
+}
+
+/// Combine registries evaluated for different systems into one, recording the store paths of each
+/// package for every system it is available on. The package information of the first system a
+/// package is available on is kept. Attributes that are excluded on every system keep the
+/// reasons from each of them.
+pub fn merge_systems(registries: impl IntoIterator<Item = (String, Registry)>) -> Registry {
+    let mut merged = Registry::new();
+
+    for (system, registry) in registries {
+        for (attribute, mut info) in registry {
+            if let Some(existing) = merged.get_mut(&attribute) {
+                match (&mut existing.excluded, &info.excluded) {
+                    // indexed on an earlier system
+                    (None, Some(_)) => {}
+                    (Some(reasons), Some(reason)) => {
+                        if !reasons.split("; ").any(|r| r == reason) {
+                            reasons.push_str("; ");
+                            reasons.push_str(reason);
+                        }
+                    }
+                    (None, None) => {
+                        let store_paths = info.store_paths.unwrap_or_default();
+                        existing
+                            .systems
+                            .get_or_insert_default()
+                            .insert(system.clone(), store_paths);
+                    }
+                    // excluded on the earlier systems, so this is the first one it's available on
+                    (Some(_), None) => {
+                        info.set_system(&system);
+                        *existing = info;
+                    }
+                }
+
+                continue;
+            }
+
+            info.set_system(&system);
+            merged.insert(attribute, info);
+        }
+    }
+
+    merged
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PackageMeta {
+    #[allow(dead_code)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub available: Option<bool>,
     #[serde(default)]
     pub broken: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub homepage: Option<OneOrList<String>>,
     #[serde(default)]
     pub insecure: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub license: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub long_description: Option<String>,
     #[serde(default)]
     pub unfree: bool,
@@ -34,14 +108,22 @@ pub struct PackageMeta {
     pub unsupported: bool,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum OneOrList<T> {
     One(T),
     List(Vec<T>),
 }
 
+impl<T> From<OneOrList<T>> for Vec<T> {
+    fn from(value: OneOrList<T>) -> Self {
+        match value {
+            OneOrList::One(one) => vec![one],
+            OneOrList::List(list) => list,
+        }
+    }
+}
+
 impl PackageInfo {
     pub fn into_rippkgs_package(self, attribute: String) -> rippkgs::Package {
         let name = self.pname;
@@ -49,17 +131,19 @@ impl PackageInfo {
         let store_paths = self.store_paths;
         let propagated_build_inputs = self.propagated_build_inputs;
         let propagated_native_build_inputs = self.propagated_native_build_inputs;
+        let systems = self.systems;
 
-        let (description, long_description) = self
-            .meta
-            .map(
-                |PackageMeta {
-                     description,
-                     long_description,
-                     ..
-                 }| (description, long_description),
-            )
-            .unzip();
+        let PackageMeta {
+            broken,
+            description,
+            homepage,
+            insecure,
+            license,
+            long_description,
+            unfree,
+            unsupported,
+            ..
+        } = self.meta.unwrap_or_default();
 
         rippkgs::Package {
             attribute,
@@ -68,8 +152,15 @@ impl PackageInfo {
             store_paths,
             propagated_build_inputs,
             propagated_native_build_inputs,
-            description: description.flatten(),
-            long_description: long_description.flatten(),
+            description,
+            long_description,
+            homepage: homepage.map(Vec::from),
+            license,
+            broken,
+            insecure,
+            unfree,
+            unsupported,
+            systems,
             score: None,
             present: None,
         }
@@ -103,4 +194,86 @@ mod tests {
             }) if store_paths.len() == 1 && store_paths["out"] == "hi"
         );
     }
+
+    #[test]
+    fn merge_systems() {
+        let registry = |json: &str| serde_json::from_str::<super::Registry>(json).unwrap();
+
+        let merged = super::merge_systems([
+            (
+                "x86_64-linux".to_string(),
+                registry(
+                    r#"{
+                        "hello": {"version": "1", "storePaths": {"out": "x86"}},
+                        "darwin-only": {"excluded": "not available on x86_64-linux"},
+                        "nowhere": {"excluded": "not available on x86_64-linux"}
+                    }"#,
+                ),
+            ),
+            (
+                "aarch64-darwin".to_string(),
+                registry(
+                    r#"{
+                        "hello": {"version": "1", "storePaths": {"out": "darwin"}},
+                        "darwin-only": {"storePaths": {"out": "darwin-only"}}
+                    }"#,
+                ),
+            ),
+            (
+                "aarch64-linux".to_string(),
+                registry(
+                    r#"{
+                        "hello": {"excluded": "failed eval"},
+                        "darwin-only": {"excluded": "not available on aarch64-linux"}
+                    }"#,
+                ),
+            ),
+        ]);
+
+        assert_matches!(
+            &merged["hello"],
+            super::PackageInfo {
+                store_paths: Some(store_paths),
+                systems: Some(systems),
+                ..
+            } if store_paths["out"] == "x86"
+                && systems["x86_64-linux"]["out"] == "x86"
+                && systems["aarch64-darwin"]["out"] == "darwin"
+        );
+
+        assert_matches!(
+            &merged["darwin-only"],
+            super::PackageInfo {
+                systems: Some(systems),
+                ..
+            } if systems.keys().eq(["aarch64-darwin"])
+        );
+
+        assert_eq!(
+            merged["nowhere"].excluded.as_deref(),
+            Some("not available on x86_64-linux")
+        );
+        assert_eq!(merged["hello"].excluded, None);
+    }
+
+    #[test]
+    fn meta() {
+        let info = serde_json::from_str::<super::PackageInfo>(
+            r#"{"meta": {"homepage": "https://example.com", "license": {"spdxId": "MIT"}, "unfree": true}}"#,
+        )
+        .unwrap();
+
+        assert_matches!(
+            info.into_rippkgs_package("hello".to_string()),
+            rippkgs::Package {
+                homepage: Some(homepage),
+                license: Some(license),
+                broken: false,
+                insecure: false,
+                unfree: true,
+                unsupported: false,
+                ..
+            } if homepage == ["https://example.com"] && license["spdxId"] == "MIT"
+        );
+    }
 }
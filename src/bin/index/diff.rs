@@ -0,0 +1,313 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    io::stdout,
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use comfy_table::TableComponent;
+use eyre::{Context, Result};
+use rippkgs::Package;
+use rusqlite::OpenFlags;
+use serde::Serialize;
+
+#[derive(Debug, Args)]
+pub struct DiffIndexes {
+    /// The index to compare from, e.g. one generated from the currently pinned nixpkgs.
+    old: PathBuf,
+
+    /// The index to compare to.
+    new: PathBuf,
+
+    /// Print the differences as json.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Diff {
+    added: Vec<Entry>,
+    removed: Vec<Entry>,
+    changed: Vec<VersionChange>,
+    /// Packages with the same version but different store paths.
+    rebuilt: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    attribute: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct VersionChange {
+    attribute: String,
+    kind: VersionChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_version: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum VersionChangeKind {
+    Upgrade,
+    Downgrade,
+    /// The versions differ but compare equal, or one of them is missing.
+    Changed,
+}
+
+pub fn diff(DiffIndexes { old, new, json }: &DiffIndexes) -> Result<()> {
+    let old = read_packages(old).with_context(|| format!("reading {}", old.display()))?;
+    let mut new = read_packages(new).with_context(|| format!("reading {}", new.display()))?;
+
+    let mut diff = Diff::default();
+
+    for (attribute, old) in old {
+        let Some(new) = new.remove(&attribute) else {
+            diff.removed.push(Entry {
+                attribute,
+                version: old.version,
+            });
+            continue;
+        };
+
+        if old.version != new.version {
+            let kind = match (old.version.as_deref(), new.version.as_deref()) {
+                (Some(old), Some(new)) => match compare_versions(old, new) {
+                    Ordering::Less => VersionChangeKind::Upgrade,
+                    Ordering::Greater => VersionChangeKind::Downgrade,
+                    Ordering::Equal => VersionChangeKind::Changed,
+                },
+                _ => VersionChangeKind::Changed,
+            };
+
+            diff.changed.push(VersionChange {
+                attribute,
+                kind,
+                old_version: old.version,
+                new_version: new.version,
+            });
+        } else if old.store_paths != new.store_paths {
+            diff.rebuilt.push(Entry {
+                attribute,
+                version: new.version,
+            });
+        }
+    }
+
+    // anything left over wasn't in the old index
+    diff.added = new
+        .into_iter()
+        .map(|(attribute, new)| Entry {
+            attribute,
+            version: new.version,
+        })
+        .collect();
+
+    if *json {
+        serde_json::to_writer(stdout(), &diff).context("printing diff")?;
+    } else {
+        print_table(diff);
+    }
+
+    Ok(())
+}
+
+fn read_packages(index: &Path) -> Result<BTreeMap<String, Package>> {
+    let conn = rusqlite::Connection::open_with_flags(
+        index,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .context("connecting to index database")?;
+
+    let version = rippkgs::schema_version(&conn).context("reading index schema version")?;
+    if version != rippkgs::SCHEMA_VERSION {
+        eyre::bail!(
+            "index has schema version {version}, but schema version {} is required. Upgrade it \
+            with `rippkgs-index migrate`.",
+            rippkgs::SCHEMA_VERSION,
+        );
+    }
+
+    let packages = conn
+        .prepare("SELECT * FROM packages")
+        .context("preparing query")?
+        .query_map([], |r| Package::try_from(r))
+        .context("executing query")?
+        .map(|package| package.map(|package| (package.attribute.clone(), package)))
+        .collect::<Result<_, _>>()
+        .context("parsing packages")?;
+
+    Ok(packages)
+}
+
+fn print_table(
+    Diff {
+        added,
+        removed,
+        changed,
+        rebuilt,
+    }: Diff,
+) {
+    let summary = format!(
+        "{} added, {} removed, {} changed, {} rebuilt",
+        added.len(),
+        removed.len(),
+        changed.len(),
+        rebuilt.len()
+    );
+
+    let mut table = comfy_table::Table::new();
+
+    table
+        .set_header(vec!["change", "attribute", "old version", "new version"])
+        .remove_style(TableComponent::HorizontalLines)
+        .remove_style(TableComponent::MiddleIntersections)
+        .remove_style(TableComponent::LeftBorderIntersections)
+        .remove_style(TableComponent::RightBorderIntersections);
+
+    for Entry { attribute, version } in added {
+        table.add_row(vec![
+            "added".to_string(),
+            attribute,
+            String::new(),
+            version.unwrap_or_default(),
+        ]);
+    }
+
+    for Entry { attribute, version } in removed {
+        table.add_row(vec![
+            "removed".to_string(),
+            attribute,
+            version.unwrap_or_default(),
+            String::new(),
+        ]);
+    }
+
+    for VersionChange {
+        attribute,
+        kind,
+        old_version,
+        new_version,
+    } in changed
+    {
+        let kind = match kind {
+            VersionChangeKind::Upgrade => "upgrade",
+            VersionChangeKind::Downgrade => "downgrade",
+            VersionChangeKind::Changed => "changed",
+        };
+
+        table.add_row(vec![
+            kind.to_string(),
+            attribute,
+            old_version.unwrap_or_default(),
+            new_version.unwrap_or_default(),
+        ]);
+    }
+
+    for Entry { attribute, version } in rebuilt {
+        let version = version.unwrap_or_default();
+        table.add_row(vec![
+            "rebuilt".to_string(),
+            attribute,
+            version.clone(),
+            version,
+        ]);
+    }
+
+    println!("{table}");
+    println!("{summary}");
+}
+
+/// Compare two versions the same way as `builtins.compareVersions`.
+fn compare_versions(v1: &str, v2: &str) -> Ordering {
+    let mut c1 = VersionComponents(v1);
+    let mut c2 = VersionComponents(v2);
+
+    while !c1.0.is_empty() || !c2.0.is_empty() {
+        let a = c1.next().unwrap_or_default();
+        let b = c2.next().unwrap_or_default();
+
+        if component_lt(a, b) {
+            return Ordering::Less;
+        } else if component_lt(b, a) {
+            return Ordering::Greater;
+        }
+    }
+
+    Ordering::Equal
+}
+
+fn component_lt(c1: &str, c2: &str) -> bool {
+    let n1 = c1.parse::<u64>().ok();
+    let n2 = c2.parse::<u64>().ok();
+
+    match (n1, n2) {
+        (Some(n1), Some(n2)) => n1 < n2,
+        _ if c1.is_empty() && n2.is_some() => true,
+        _ if c1 == "pre" && c2 != "pre" => true,
+        _ if c2 == "pre" => false,
+        // assume that `2.3a` < `2.3.1`
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => c1 < c2,
+    }
+}
+
+/// Splits a version into runs of digits or runs of other characters, separated by `.` and `-`.
+struct VersionComponents<'a>(&'a str);
+
+impl<'a> Iterator for VersionComponents<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.0.trim_start_matches(['.', '-']);
+        self.0 = rest;
+        let first = rest.chars().next()?;
+
+        let end = if first.is_ascii_digit() {
+            rest.find(|c: char| !c.is_ascii_digit())
+        } else {
+            rest.find(|c: char| c.is_ascii_digit() || c == '.' || c == '-')
+        }
+        .unwrap_or(rest.len());
+
+        let (component, rest) = rest.split_at(end);
+        self.0 = rest;
+        Some(component)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::compare_versions;
+
+    #[test]
+    fn compare_versions_like_nix() {
+        for (v1, v2, expected) in [
+            ("1.0", "2.3", Ordering::Less),
+            ("2.1", "2.3", Ordering::Less),
+            ("2.3", "2.3", Ordering::Equal),
+            ("2.5", "2.3", Ordering::Greater),
+            ("3.1", "2.3", Ordering::Greater),
+            ("2.3.1", "2.3", Ordering::Greater),
+            ("2.3.1", "2.3a", Ordering::Greater),
+            ("2.3pre1", "2.3", Ordering::Less),
+            ("2.3pre3", "2.3pre12", Ordering::Less),
+            ("2.3a", "2.3c", Ordering::Less),
+            ("2.3pre1", "2.3c", Ordering::Less),
+            ("2.3pre1", "2.3q", Ordering::Less),
+            ("1.0", "1.0.0", Ordering::Less),
+            ("1.0.", "1.0", Ordering::Equal),
+            ("5.9", "5.9-unstable-2024-01-01", Ordering::Less),
+        ] {
+            assert_eq!(compare_versions(v1, v2), expected, "{v1} vs {v2}");
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{stdin, BufRead, BufReader, BufWriter},
+    path::Path,
+    time::Instant,
+};
+
+use eyre::{Context, Result};
+use rippkgs::IndexMetadata;
+
+use crate::{
+    compression::{self, Compression},
+    data::{self, Registry},
+    formats::{self, Format},
+    progress::{self, Event},
+    registry::{self, Sink},
+    EvalOpts,
+};
+
+/// A source of package registries. Everything that produces packages for an index goes through
+/// this, so new evaluators don't need to know how the index is written.
+pub trait Evaluator {
+    /// Describe what is being indexed. This is called once, before any registry is evaluated.
+    fn metadata(&mut self) -> Result<IndexMetadata>;
+
+    /// Evaluate the registry for `system`, or for the current system if `None`, passing each entry
+    /// to `sink`.
+    fn registry(&mut self, system: Option<&str>, sink: &mut Sink) -> Result<()>;
+}
+
+/// Reads a registry that was generated ahead of time, e.g. with `--save-registry`, or another
+/// package listing in one of the supported [`Format`]s. The file may be compressed, and `-` reads
+/// stdin. It holds a single registry, which is returned whatever system is asked for.
+pub struct RegistryFile<'a>(pub &'a Path, pub Format);
+
+impl RegistryFile<'_> {
+    fn is_stdin(&self) -> bool {
+        self.0 == Path::new("-")
+    }
+}
+
+impl Evaluator for RegistryFile<'_> {
+    fn metadata(&mut self) -> Result<IndexMetadata> {
+        let source = match self.is_stdin() {
+            true => "<stdin>".to_string(),
+            false => self.0.display().to_string(),
+        };
+
+        Ok(IndexMetadata {
+            source: Some(source),
+            ..IndexMetadata::new()
+        })
+    }
+
+    fn registry(&mut self, _system: Option<&str>, sink: &mut Sink) -> Result<()> {
+        let reader: Box<dyn BufRead> = if self.is_stdin() {
+            Box::new(stdin().lock())
+        } else {
+            let f = File::options()
+                .read(true)
+                .open(self.0)
+                .context("opening registry file")?;
+            Box::new(BufReader::new(f))
+        };
+
+        let start = Instant::now();
+        let reader = compression::decompress(reader, (!self.is_stdin()).then_some(self.0))
+            .context("reading registry file")?;
+        formats::stream(reader, self.1, sink).context("reading registry JSON")?;
+
+        progress::report(
+            Event::Finished {
+                step: "read registry",
+                seconds: start.elapsed().as_secs_f64(),
+            },
+            true,
+        );
+
+        Ok(())
+    }
+}
+
+/// Returns canned registries without running anything. Entries are passed to the sink in
+/// attribute order, so runs are deterministic.
+#[cfg(test)]
+pub struct Fake {
+    pub metadata: IndexMetadata,
+    /// The result for each system, taken when that system is evaluated. Evaluating a system
+    /// without an entry fails.
+    pub registries: std::collections::BTreeMap<
+        Option<String>,
+        Result<std::collections::BTreeMap<String, data::PackageInfo>, crate::nix::EvalError>,
+    >,
+}
+
+#[cfg(test)]
+impl Evaluator for Fake {
+    fn metadata(&mut self) -> Result<IndexMetadata> {
+        Ok(self.metadata.clone())
+    }
+
+    fn registry(&mut self, system: Option<&str>, sink: &mut Sink) -> Result<()> {
+        let registry = self
+            .registries
+            .remove(&system.map(str::to_string))
+            .unwrap_or_else(|| {
+                Err(crate::nix::EvalError::Evaluation {
+                    message: format!("no registry for {system:?}"),
+                    trace: vec![],
+                })
+            })?;
+
+        registry
+            .into_iter()
+            .try_for_each(|(attr, info)| sink(attr, info))
+    }
+}
+
+/// Counts the attributes, and the scopes they are in, that an evaluation has produced.
+struct EvalProgress<'a> {
+    system: Option<&'a str>,
+    attributes: u64,
+    scopes: HashSet<String>,
+}
+
+impl<'a> EvalProgress<'a> {
+    fn new(system: Option<&'a str>) -> Self {
+        Self {
+            system,
+            attributes: 0,
+            scopes: HashSet::new(),
+        }
+    }
+
+    fn record(&mut self, attr: &str) {
+        self.attributes += 1;
+        if let Some((scope, _)) = attr.split_once('.') {
+            if !self.scopes.contains(scope) {
+                self.scopes.insert(scope.to_string());
+            }
+        }
+
+        self.report(false);
+    }
+
+    fn report(&self, force: bool) {
+        progress::report(
+            Event::Evaluating {
+                system: self.system,
+                attributes: self.attributes,
+                scopes: self.scopes.len() as u64,
+            },
+            force,
+        );
+    }
+}
+
+/// Evaluate registries with `evaluator` for each of the requested systems, or once for the
+/// current system if none are given, passing the entries to `sink` and returning the index
+/// metadata.
+///
+/// A single evaluation, for one system or the current one, is streamed straight to `sink`.
+/// Registries for several systems have to be merged before any entry is complete, so they are
+/// collected in memory first.
+pub fn evaluate(
+    eval: &EvalOpts,
+    evaluator: &mut dyn Evaluator,
+    sink: &mut Sink,
+) -> Result<IndexMetadata> {
+    eval.reset_error_log()?;
+
+    let metadata = evaluator.metadata().context("reading metadata")?;
+
+    let mut writer = eval
+        .save_registry
+        .as_ref()
+        .map(|save_registry| {
+            let f = File::options()
+                .write(true)
+                .truncate(true)
+                .create(true)
+                .open(save_registry)
+                .context("opening registry file")?;
+
+            let compression = eval
+                .compress
+                .unwrap_or_else(|| Compression::from_extension(save_registry));
+            let encoder = compression::Encoder::new(BufWriter::new(f), compression)
+                .context("starting registry compression")?;
+
+            Ok::<_, eyre::Report>(registry::Writer::new(encoder))
+        })
+        .transpose()?;
+
+    let mut sink = |attr: String, info: data::PackageInfo| {
+        if let Some(writer) = &mut writer {
+            writer
+                .write(&attr, &info)
+                .context("writing registry file")?;
+        }
+
+        sink(attr, info)
+    };
+
+    if eval.systems.len() <= 1 {
+        let system = eval.systems.first().map(String::as_str);
+        let mut progress = EvalProgress::new(system);
+        evaluator
+            .registry(system, &mut |attr, mut info| {
+                progress.record(&attr);
+                if let Some(system) = system {
+                    info.set_system(system);
+                }
+                sink(attr, info)
+            })
+            .with_context(|| match system {
+                Some(system) => format!("evaluating registry for {system}"),
+                None => "evaluating registry".to_string(),
+            })?;
+        progress.report(true);
+    } else {
+        let registries = eval
+            .systems
+            .iter()
+            .map(|system| {
+                let mut registry = Registry::new();
+                let mut progress = EvalProgress::new(Some(system));
+                evaluator
+                    .registry(Some(system), &mut |attr, info| {
+                        progress.record(&attr);
+                        registry.insert(attr, info);
+                        Ok(())
+                    })
+                    .with_context(|| format!("evaluating registry for {system}"))?;
+                progress.report(true);
+
+                Ok((system.clone(), registry))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let registry = data::merge_systems(registries);
+        progress::set_total(registry.len() as u64);
+
+        registry
+            .into_iter()
+            .try_for_each(|(attr, info)| sink(attr, info))?;
+    }
+
+    if let Some(writer) = writer {
+        let encoder = writer.finish().context("writing registry file")?;
+        encoder.finish().context("compressing registry file")?;
+    }
+
+    Ok(metadata)
+}
@@ -0,0 +1,264 @@
+use std::{
+    io::{BufRead, BufReader},
+    thread,
+    time::Instant,
+};
+
+use clap::Args;
+use eyre::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    data::PackageInfo,
+    nix, nix_string,
+    progress::{self, Event},
+    registry::Sink,
+    scope::ScopeOpts,
+    EvalOpts,
+};
+
+#[derive(Debug, Args)]
+pub struct EvalJobsOpts {
+    /// Evaluate with `nix-eval-jobs`, which evaluates packages in parallel. This produces the same
+    /// index as the default `nix eval` evaluation, except that packages whose derivation fails to
+    /// evaluate are excluded rather than recorded with `<broken>` store paths. Packages that the
+    /// nixpkgs config refuses, e.g. unfree ones, are recorded as `nix eval` records them, which
+    /// takes a second evaluation of nixpkgs in every worker.
+    #[clap(long)]
+    pub eval_jobs: bool,
+
+    /// The nix-eval-jobs executable to run. Defaults to `nix-eval-jobs` from `PATH`.
+    #[clap(long, value_name = "PATH", requires = "eval_jobs")]
+    nix_eval_jobs: Option<String>,
+
+    /// The number of `nix-eval-jobs` workers. Defaults to the number of CPUs.
+    #[clap(long, requires = "eval_jobs")]
+    workers: Option<usize>,
+
+    /// The memory limit of each `nix-eval-jobs` worker in MiB. Workers that exceed it are
+    /// restarted.
+    #[clap(long, value_name = "MIB", requires = "eval_jobs")]
+    worker_memory: Option<u64>,
+}
+
+/// A line of `nix-eval-jobs` output.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Job {
+    attr_path: Vec<String>,
+    /// The `genRegistry` entry for the package.
+    #[serde(default)]
+    extra_value: Option<PackageInfo>,
+    error: Option<String>,
+}
+
+impl Job {
+    /// The registry entry `genRegistry` would produce for this job, if any.
+    fn into_registry_entry(self, scopes: &ScopeOpts) -> Option<(String, PackageInfo)> {
+        // nix-eval-jobs descends into every package set with `recurseForDerivations`
+        if !scopes.indexes(&self.attr_path) {
+            return None;
+        }
+
+        let info = match self.error {
+            Some(error) => {
+                let message = match nix::EvalError::from_stderr(&error) {
+                    nix::EvalError::Evaluation { message, .. } => message,
+                    err => err.to_string(),
+                };
+
+                PackageInfo {
+                    excluded: Some(format!("failed eval: {message}")),
+                    ..PackageInfo::default()
+                }
+            }
+            None => self.extra_value?,
+        };
+
+        Some((self.attr_path.join("."), info))
+    }
+}
+
+/// Evaluate the packages of nixpkgs, imported with `nixpkgs_arg`, with `nix-eval-jobs`, streaming
+/// the registry entries to `sink` as the workers produce them.
+///
+/// The workers walk a copy of nixpkgs that allows every package, since they can only report
+/// packages whose derivation evaluates. Each derivation is tagged with the `genRegistry` entry of
+/// the same attribute in nixpkgs as configured, so the entries are the ones `nix eval` on the
+/// whole package set would produce.
+pub fn eval_registry(
+    eval: &EvalOpts,
+    EvalJobsOpts {
+        nix_eval_jobs,
+        workers,
+        worker_memory,
+        ..
+    }: &EvalJobsOpts,
+    scopes: &ScopeOpts,
+    nixpkgs_arg: &str,
+    system: Option<&str>,
+    extra_args: &[&str],
+    sink: &mut Sink,
+) -> Result<()> {
+    let system = match system {
+        Some(system) => nix_string(system),
+        None => "builtins.currentSystem".to_string(),
+    };
+
+    let expr = format!(
+        r#"
+let lib = import <nixpkgs/lib>;
+    genRegistry = {gen_registry};
+    system = {system};
+    arg = ({nixpkgs_arg});
+    allowAll = config: config // {{
+      allowUnfree = true;
+      allowBroken = true;
+      allowInsecurePredicate = _: true;
+      allowUnsupportedSystem = true;
+      blocklistedLicenses = [ ];
+    }};
+    allowedArg = arg // {{
+      config =
+        if lib.isFunction (arg.config or {{ }})
+        then (args: allowAll (arg.config args))
+        else allowAll (arg.config or {{ }});
+    }};
+    pkgs = {pkgs};
+    tag = path: lib.mapAttrs (name: value: let
+      path' = path ++ [ name ];
+    in
+      if lib.isDerivation value
+      then value // {{
+        rippkgsEntry = (genRegistry {{ inherit lib system; }} {{
+          package = lib.attrByPath path' null pkgs;
+        }}).package;
+      }}
+      else if builtins.isAttrs value
+      then tag path' value
+      else value);
+in tag [ ] ({allowed_pkgs})
+        "#,
+        gen_registry = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/lib/genRegistry.nix")),
+        pkgs = scopes.eval_jobs_expr("import <nixpkgs> arg"),
+        allowed_pkgs = scopes.eval_jobs_expr("import <nixpkgs> allowedArg"),
+    );
+
+    let workers = workers
+        .or_else(|| thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(1)
+        .to_string();
+    let worker_memory = worker_memory.map(|mib| mib.to_string());
+
+    let mut args = vec![
+        "--impure",
+        "--expr",
+        &expr,
+        "--apply",
+        "drv: drv.rippkgsEntry",
+        "--workers",
+        &workers,
+    ];
+    if let Some(worker_memory) = &worker_memory {
+        args.extend(["--max-memory-size", worker_memory]);
+    }
+    args.extend_from_slice(extra_args);
+    args.extend(eval.nix_args());
+
+    let start = Instant::now();
+    let mut failed = 0;
+
+    nix::stream(
+        nix_eval_jobs.as_deref().unwrap_or("nix-eval-jobs"),
+        &args,
+        eval.error_log.as_deref(),
+        eval.limits(),
+        |stdout| {
+            for line in BufReader::new(stdout).lines() {
+                let line = line.context("reading nix-eval-jobs output")?;
+                let job = serde_json::from_str::<Job>(&line).map_err(nix::EvalError::Json)?;
+
+                if job.error.is_some() {
+                    failed += 1;
+                }
+
+                if let Some((attribute, info)) = job.into_registry_entry(scopes) {
+                    sink(attribute, info)?;
+                }
+            }
+
+            Ok(())
+        },
+    )
+    .context("running `nix-eval-jobs`")?;
+
+    if failed > 0 {
+        progress::report(
+            Event::Warning {
+                message: &format!("{failed} attributes failed to evaluate"),
+            },
+            true,
+        );
+    }
+
+    progress::report(
+        Event::Finished {
+            step: "evaluated registry",
+            seconds: start.elapsed().as_secs_f64(),
+        },
+        true,
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Job;
+    use crate::scope::ScopeOpts;
+
+    fn entry(line: &str) -> Option<String> {
+        serde_json::from_str::<Job>(line)
+            .unwrap()
+            .into_registry_entry(&ScopeOpts::default())
+            .map(|(attribute, info)| format!("{attribute} {}", info.pname.unwrap()))
+    }
+
+    fn excluded(line: &str) -> Option<String> {
+        serde_json::from_str::<Job>(line)
+            .unwrap()
+            .into_registry_entry(&ScopeOpts::default())
+            .and_then(|(_, info)| info.excluded)
+    }
+
+    #[test]
+    fn job_registry_entries() {
+        assert_eq!(
+            entry(
+                r#"{"attr":"hello","attrPath":["hello"],"drvPath":"/nix/store/x.drv","name":"hello-2.12.1","outputs":{"out":"/nix/store/y"},"system":"x86_64-linux","extraValue":{"pname":"hello","storePaths":{"out":"y"}}}"#
+            ),
+            Some("hello hello".to_string())
+        );
+        assert_eq!(
+            entry(
+                r#"{"attr":"python3Packages.requests","attrPath":["python3Packages","requests"],"extraValue":{"pname":"requests"}}"#
+            ),
+            Some("python3Packages.requests requests".to_string())
+        );
+
+        assert_eq!(
+            excluded(
+                r#"{"attr":"unavailable","attrPath":["unavailable"],"extraValue":{"excluded":"not available on x86_64-linux"}}"#
+            ),
+            Some("not available on x86_64-linux".to_string())
+        );
+        assert_eq!(
+            excluded(r#"{"attr":"broken","attrPath":["broken"],"error":"error: boom"}"#),
+            Some("failed eval: boom".to_string())
+        );
+        assert_eq!(
+            entry(r#"{"attr":"a.b.c","attrPath":["a","b","c"],"extraValue":{"pname":"c"}}"#),
+            None
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::{fs, path::PathBuf};
+
+use clap::Args;
+use eyre::{Context, Result};
+use regex::Regex;
+
+use crate::progress::{self, Event};
+
+#[derive(Debug, Default, Args)]
+pub struct FilterOpts {
+    /// Only index attributes matching this pattern. May be given several times, in which case
+    /// attributes matching any of them are indexed. Patterns are globs, where `*` matches within
+    /// one segment of the attribute path and `**` matches across segments, or regexes when
+    /// prefixed with `regex:`. Both have to match the whole attribute path.
+    #[clap(long = "include", value_name = "PATTERN")]
+    includes: Vec<String>,
+
+    /// Leave out attributes matching this pattern, even if they match an `--include`. May be
+    /// given several times.
+    #[clap(long = "exclude", value_name = "PATTERN")]
+    excludes: Vec<String>,
+
+    /// Read `--include` patterns from a file, one per line. Blank lines and lines starting with
+    /// `#` are ignored.
+    #[clap(long, value_name = "FILE")]
+    include_from: Vec<PathBuf>,
+
+    /// Read `--exclude` patterns from a file, one per line. Blank lines and lines starting with
+    /// `#` are ignored.
+    #[clap(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+}
+
+impl FilterOpts {
+    /// Compile the rules, reading the pattern files.
+    pub fn filter(&self) -> Result<Filter> {
+        let includes = rules("--include", &self.includes, &self.include_from)
+            .context("reading include patterns")?;
+        let excludes = rules("--exclude", &self.excludes, &self.exclude_from)
+            .context("reading exclude patterns")?;
+
+        Ok(Filter {
+            includes,
+            excludes,
+            not_included: 0,
+        })
+    }
+}
+
+fn rules(flag: &str, patterns: &[String], files: &[PathBuf]) -> Result<Vec<Rule>> {
+    let mut rules = patterns
+        .iter()
+        .map(|pattern| Rule::new(format!("{flag} {pattern}"), pattern))
+        .collect::<Result<Vec<_>>>()?;
+
+    for file in files {
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("reading pattern file {}", file.display()))?;
+
+        for (i, line) in contents.lines().enumerate() {
+            let pattern = line.trim();
+            if pattern.is_empty() || pattern.starts_with('#') {
+                continue;
+            }
+
+            let source = format!("{}:{} {pattern}", file.display(), i + 1);
+            rules.push(Rule::new(source, pattern)?);
+        }
+    }
+
+    Ok(rules)
+}
+
+struct Rule {
+    /// Where the rule came from, for the summary.
+    source: String,
+    pattern: Regex,
+    /// The number of entries this rule left out of the index.
+    removed: u64,
+}
+
+impl Rule {
+    fn new(source: String, pattern: &str) -> Result<Self> {
+        let pattern = match pattern.strip_prefix("regex:") {
+            Some(regex) => format!("^(?:{regex})$"),
+            None => glob_regex(pattern),
+        };
+
+        let pattern = Regex::new(&pattern).with_context(|| format!("invalid pattern {source}"))?;
+
+        Ok(Self {
+            source,
+            pattern,
+            removed: 0,
+        })
+    }
+}
+
+/// The anchored regex matching the same attribute paths as `glob`.
+fn glob_regex(glob: &str) -> String {
+    let mut regex = "^".to_string();
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.next_if_eq(&'*').is_some() => regex.push_str(".*"),
+            '*' => regex.push_str(r"[^.]*"),
+            '?' => regex.push_str(r"[^.]"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+/// Decides which registry entries are written to the index, counting what each rule removes.
+pub struct Filter {
+    includes: Vec<Rule>,
+    excludes: Vec<Rule>,
+    /// The number of entries that matched none of the include rules.
+    not_included: u64,
+}
+
+impl Filter {
+    /// Why `attribute` is left out of the index, or `None` if it should be indexed.
+    pub fn exclusion(&mut self, attribute: &str) -> Option<String> {
+        if !self.includes.is_empty()
+            && !self
+                .includes
+                .iter()
+                .any(|rule| rule.pattern.is_match(attribute))
+        {
+            self.not_included += 1;
+            return Some("filtered by --include".to_string());
+        }
+
+        let rule = self
+            .excludes
+            .iter_mut()
+            .find(|rule| rule.pattern.is_match(attribute))?;
+        rule.removed += 1;
+
+        Some(format!("filtered by {}", rule.source))
+    }
+
+    /// Report how many entries each rule removed.
+    pub fn report(&self) {
+        if !self.includes.is_empty() {
+            progress::report(
+                Event::Filtered {
+                    rule: "--include",
+                    removed: self.not_included,
+                },
+                true,
+            );
+        }
+
+        for rule in &self.excludes {
+            progress::report(
+                Event::Filtered {
+                    rule: &rule.source,
+                    removed: rule.removed,
+                },
+                true,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilterOpts;
+
+    #[test]
+    fn filter_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let deny = dir.path().join("deny");
+        std::fs::write(&deny, "# no python 2\n\npython2Packages.*\n").unwrap();
+
+        let mut filter = FilterOpts {
+            includes: vec![
+                "python*Packages.*".to_string(),
+                "regex:hello|vim".to_string(),
+            ],
+            excludes: vec!["**.requests".to_string()],
+            exclude_from: vec![deny.clone()],
+            ..FilterOpts::default()
+        }
+        .filter()
+        .unwrap();
+
+        assert_eq!(filter.exclusion("hello"), None);
+        assert_eq!(filter.exclusion("python3Packages.numpy"), None);
+        assert_eq!(
+            filter.exclusion("hello-unwrapped").as_deref(),
+            Some("filtered by --include")
+        );
+        assert!(filter.exclusion("python3Packages.numpy.dist").is_some());
+        assert_eq!(
+            filter.exclusion("python3Packages.requests").as_deref(),
+            Some("filtered by --exclude **.requests")
+        );
+        assert_eq!(
+            filter.exclusion("python2Packages.numpy"),
+            Some(format!(
+                "filtered by {}:3 python2Packages.*",
+                deny.display()
+            ))
+        );
+
+        assert_eq!(filter.not_included, 2);
+        assert_eq!(
+            filter
+                .excludes
+                .iter()
+                .map(|rule| rule.removed)
+                .collect::<Vec<_>>(),
+            [1, 1]
+        );
+    }
+}
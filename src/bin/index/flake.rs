@@ -0,0 +1,172 @@
+use clap::Args;
+use eyre::{Context, Result};
+use rippkgs::IndexMetadata;
+use serde::Deserialize;
+
+use crate::{
+    eval::Evaluator, filter::FilterOpts, nix::EvalError, nix_string, registry::Sink,
+    run_gen_registry, EvalOpts, OutputOpts,
+};
+
+#[derive(Debug, Args)]
+pub struct IndexFlake {
+    #[clap(flatten)]
+    pub eval: EvalOpts,
+
+    /// The flake to index, e.g. `.` or `github:owner/repo`. Its `legacyPackages.<system>` and
+    /// `packages.<system>` outputs are indexed, preferring `packages` when both define an
+    /// attribute.
+    flake: String,
+
+    #[clap(flatten)]
+    pub filter: FilterOpts,
+
+    #[clap(flatten)]
+    pub output: OutputOpts,
+}
+
+/// The parts of `nix flake metadata --json` we care about.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlakeMetadata {
+    /// The locked flake reference.
+    url: String,
+    revision: Option<String>,
+    dirty_revision: Option<String>,
+}
+
+impl IndexFlake {
+    pub fn evaluator(&self) -> FlakeEvaluator<'_> {
+        FlakeEvaluator {
+            eval: &self.eval,
+            flake: &self.flake,
+            url: None,
+        }
+    }
+}
+
+/// Evaluates the package outputs of a flake with the nix CLI.
+pub struct FlakeEvaluator<'a> {
+    eval: &'a EvalOpts,
+    flake: &'a str,
+    /// The locked flake reference, once the flake metadata has been read.
+    url: Option<String>,
+}
+
+impl Evaluator for FlakeEvaluator<'_> {
+    fn metadata(&mut self) -> Result<IndexMetadata> {
+        let FlakeMetadata {
+            url,
+            revision,
+            dirty_revision,
+        } = flake_metadata(self.eval, self.flake).context("reading flake metadata")?;
+
+        // evaluate the locked reference so the index matches the recorded revision
+        self.url = Some(url.clone());
+
+        Ok(IndexMetadata {
+            source: Some(url),
+            revision: revision.or(dirty_revision),
+            systems: (!self.eval.systems.is_empty()).then(|| self.eval.systems.clone()),
+            ..IndexMetadata::new()
+        })
+    }
+
+    fn registry(&mut self, system: Option<&str>, sink: &mut Sink) -> Result<()> {
+        let url = self.url.as_deref().unwrap_or(self.flake);
+        eval_registry(self.eval, url, system, sink)
+    }
+}
+
+fn flake_metadata(eval: &EvalOpts, flake: &str) -> Result<FlakeMetadata> {
+    let output = eval
+        .run_nix(&["flake", "metadata", "--json", flake])
+        .context("running `nix flake metadata`")?;
+
+    serde_json::from_slice(&output)
+        .map_err(EvalError::Json)
+        .context("reading flake metadata JSON")
+}
+
+fn eval_registry(eval: &EvalOpts, url: &str, system: Option<&str>, sink: &mut Sink) -> Result<()> {
+    let system = match system {
+        Some(system) => nix_string(system),
+        None => "builtins.currentSystem".to_string(),
+    };
+
+    // nixpkgs itself has its own `lib`, and other flakes usually have it as an input
+    let apply_arg = format!(
+        r#"
+genRegistry:
+
+let flake = builtins.getFlake {url};
+    system = {system};
+    lib =
+      if flake ? lib.isDerivation
+      then flake.lib
+      else flake.inputs.nixpkgs.lib or (builtins.getFlake "nixpkgs").lib;
+    pkgs = flake.legacyPackages.${{system}} or {{ }}
+      // flake.packages.${{system}} or {{ }};
+in genRegistry {{ inherit lib system; }} pkgs
+        "#,
+        url = nix_string(url),
+    );
+
+    run_gen_registry(eval, &apply_arg, &[], sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        filter::FilterOpts,
+        tests::{fake_nix, fake_nix_args, read_index},
+        EvalOpts, OutputOpts,
+    };
+
+    #[test]
+    fn index_flake() {
+        let dir = tempfile::tempdir().unwrap();
+        let nix = fake_nix(
+            dir.path(),
+            "nix",
+            r#"
+case "$1" in
+  flake) echo '{"url": "path:/src/my \"flake\"?rev=abc", "revision": "abc"}' ;;
+  eval) echo '{"hello": {"pname": "hello"}}' ;;
+esac
+            "#,
+        );
+
+        let flake = super::IndexFlake {
+            eval: EvalOpts {
+                nix: Some(nix),
+                systems: vec!["x86_64-linux".to_string()],
+                ..EvalOpts::default()
+            },
+            flake: ".".to_string(),
+            filter: FilterOpts::default(),
+            output: OutputOpts {
+                output: dir.path().join("index.sqlite"),
+                update: false,
+            },
+        };
+        crate::index(&flake.output, &flake.eval, &flake.filter, flake.evaluator()).unwrap();
+
+        let (packages, metadata) = read_index(&flake.output.output);
+        assert!(packages["hello"].available_on("x86_64-linux"));
+        assert_eq!(metadata.revision.as_deref(), Some("abc"));
+        assert_eq!(
+            metadata.source.as_deref(),
+            Some(r#"path:/src/my "flake"?rev=abc"#)
+        );
+
+        // the locked reference is evaluated, escaped as a nix string
+        let args = fake_nix_args(dir.path(), "nix");
+        assert!(args.starts_with("flake\nmetadata\n--json\n.\n"), "{args}");
+        assert!(
+            args.contains(r#"builtins.getFlake "path:/src/my \"flake\"?rev=abc";"#),
+            "{args}"
+        );
+        assert!(args.contains(r#"system = "x86_64-linux";"#), "{args}");
+    }
+}
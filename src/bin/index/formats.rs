@@ -0,0 +1,207 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+};
+
+use clap::ValueEnum;
+use eyre::Result;
+use serde::Deserialize;
+
+use crate::{
+    data::PackageInfo,
+    packages_json::{NixEnvPackage, UNKNOWN_STORE_PATH},
+    registry::{self, Sink},
+};
+
+/// The formats a registry file can be imported from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Detect the format from the first entry.
+    #[default]
+    Auto,
+    /// The output of `genRegistry`, or a registry saved with `--save-registry`.
+    Registry,
+    /// The output of `nix-env -qaP --json --meta`.
+    NixEnv,
+    /// The output of `nix search --json`.
+    NixSearch,
+}
+
+/// An entry of any of the formats, with the fields that tell them apart.
+#[derive(Debug, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    info: PackageInfo,
+    /// The derivation name, which only `nix-env` prints.
+    name: Option<String>,
+    /// The outputs, which only `nix-env` prints.
+    outputs: Option<HashMap<String, Option<String>>>,
+    /// The description, which `nix search` prints outside of `meta`.
+    description: Option<String>,
+}
+
+impl Entry {
+    fn format(&self, attribute: &str) -> Format {
+        let flake_output = ["legacyPackages.", "packages."]
+            .iter()
+            .any(|prefix| attribute.starts_with(prefix));
+
+        if self.name.is_some() || self.outputs.is_some() {
+            Format::NixEnv
+        } else if self.description.is_some() || flake_output {
+            Format::NixSearch
+        } else {
+            Format::Registry
+        }
+    }
+
+    /// The registry entry for this entry of a file in `format`.
+    fn into_registry_entry(self, attribute: String, format: Format) -> (String, PackageInfo) {
+        let Entry {
+            info,
+            name,
+            outputs,
+            description,
+        } = self;
+
+        match format {
+            Format::Auto | Format::Registry => (attribute, info),
+            Format::NixEnv => {
+                let package = NixEnvPackage {
+                    name,
+                    pname: info.pname,
+                    version: info.version,
+                    outputs: outputs.unwrap_or_default(),
+                    meta: info.meta,
+                };
+
+                (attribute, package.into())
+            }
+            Format::NixSearch => {
+                let mut meta = info.meta.unwrap_or_default();
+                meta.description = meta.description.or(description);
+
+                let info = PackageInfo {
+                    meta: Some(meta),
+                    // `nix search` doesn't print outputs, but every package it finds has `out`
+                    store_paths: Some(HashMap::from([(
+                        "out".to_string(),
+                        UNKNOWN_STORE_PATH.to_string(),
+                    )])),
+                    ..info
+                };
+
+                (flake_attribute(attribute), info)
+            }
+        }
+    }
+}
+
+/// The attribute path within the package set of a flake output path, like
+/// `legacyPackages.x86_64-linux.hello`.
+fn flake_attribute(attribute: String) -> String {
+    let mut segments = attribute.splitn(3, '.');
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some("legacyPackages" | "packages"), Some(_system), Some(attribute)) => {
+            attribute.to_string()
+        }
+        _ => attribute,
+    }
+}
+
+/// Parse a registry file in `format` from `reader`, passing each entry to `sink` as a registry
+/// entry as soon as it has been parsed. The `legacyPackages` entries of `nix search` output are
+/// held back until the end, since a `packages` entry for the same attribute may still follow.
+pub fn stream(reader: impl Read, format: Format, sink: &mut Sink) -> Result<()> {
+    if format == Format::Registry {
+        return registry::stream(reader, sink);
+    }
+
+    let mut format = format;
+    // attributes found in `packages`, which are preferred over the same attribute in
+    // `legacyPackages` like the flake subcommand does, whichever comes first
+    let mut from_packages = HashSet::new();
+    let mut from_legacy_packages = vec![];
+
+    registry::stream_entries(reader, &mut |attribute, entry: Entry| {
+        if format == Format::Auto {
+            format = entry.format(&attribute);
+        }
+
+        let output = attribute.split('.').next().unwrap_or_default().to_string();
+        let (attribute, info) = entry.into_registry_entry(attribute, format);
+
+        if format == Format::NixSearch {
+            match output.as_str() {
+                "packages" => {
+                    from_packages.insert(attribute.clone());
+                }
+                "legacyPackages" => {
+                    from_legacy_packages.push((attribute, info));
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        sink(attribute, info)
+    })?;
+
+    from_legacy_packages
+        .into_iter()
+        .filter(|(attribute, _)| !from_packages.contains(attribute))
+        .try_for_each(|(attribute, info)| sink(attribute, info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Format;
+    use crate::data::PackageInfo;
+
+    fn read(json: &str, format: Format) -> Vec<(String, PackageInfo)> {
+        let mut entries = vec![];
+        super::stream(json.as_bytes(), format, &mut |attribute, info| {
+            entries.push((attribute, info));
+            Ok(())
+        })
+        .unwrap();
+        entries
+    }
+
+    #[test]
+    fn formats() {
+        let nix_env = r#"{"nixpkgs.hello": {"name": "hello-2.12.1", "pname": "hello", "version": "2.12.1", "outputName": "out", "outputs": {"out": "/nix/store/abc-hello-2.12.1"}, "system": "x86_64-linux", "meta": {"description": "greeting"}}}"#;
+        for format in [Format::Auto, Format::NixEnv] {
+            let [(attribute, info)] = read(nix_env, format).try_into().unwrap();
+            assert_eq!(attribute, "nixpkgs.hello");
+            assert_eq!(info.store_paths.unwrap()["out"], "abc-hello-2.12.1");
+            assert_eq!(info.meta.unwrap().description.as_deref(), Some("greeting"));
+        }
+
+        let nix_search = r#"{"legacyPackages.x86_64-linux.hello": {"pname": "hello", "version": "2.12.1", "description": "greeting"}}"#;
+        for format in [Format::Auto, Format::NixSearch] {
+            let [(attribute, info)] = read(nix_search, format).try_into().unwrap();
+            assert_eq!(attribute, "hello");
+            assert_eq!(info.pname.as_deref(), Some("hello"));
+            assert!(info.store_paths.unwrap().contains_key("out"));
+            assert_eq!(info.meta.unwrap().description.as_deref(), Some("greeting"));
+        }
+
+        // `packages` wins over `legacyPackages` in either order
+        for both in [
+            r#"{"packages.x86_64-linux.hello": {"pname": "hello", "version": "2"}, "legacyPackages.x86_64-linux.hello": {"pname": "hello", "version": "1"}}"#,
+            r#"{"legacyPackages.x86_64-linux.hello": {"pname": "hello", "version": "1"}, "packages.x86_64-linux.hello": {"pname": "hello", "version": "2"}}"#,
+        ] {
+            let [(attribute, info)] = read(both, Format::Auto).try_into().unwrap();
+            assert_eq!(attribute, "hello");
+            assert_eq!(info.version.as_deref(), Some("2"));
+        }
+
+        let registry = r#"{"hello": {"pname": "hello", "storePaths": {"out": "abc-hello"}}}"#;
+        for format in [Format::Auto, Format::Registry] {
+            let [(attribute, info)] = read(registry, format).try_into().unwrap();
+            assert_eq!(attribute, "hello");
+            assert_eq!(info.store_paths.unwrap()["out"], "abc-hello");
+        }
+    }
+}
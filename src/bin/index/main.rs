@@ -1,25 +1,57 @@
+mod compression;
 mod data;
+mod diff;
+mod eval;
+mod eval_jobs;
+mod filter;
+mod flake;
+mod formats;
+mod nix;
+mod packages_json;
+mod progress;
+mod registry;
+mod scope;
 
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::Write,
+    collections::{BTreeSet, HashMap},
+    fs::{File, Permissions},
+    io::{self, BufReader},
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
     path::{Path, PathBuf},
-    process::Command,
-    time::Instant,
+    process::{ChildStdout, ExitCode},
+    sync::OnceLock,
+    time::{Duration, Instant},
 };
 
 use clap::{Args, Parser, Subcommand};
-use data::{PackageInfo, Registry};
+use eval::Evaluator;
 use eyre::{Context, Result};
-use rippkgs::Package;
+use filter::Filter;
+use progress::Event;
+use registry::Sink;
+use rippkgs::{Exclusion, IndexMetadata, Package};
 use rusqlite::OpenFlags;
+use serde::Deserialize;
 
 #[derive(Debug, Parser)]
-#[clap(about = "Generate an index for use with the rippkgs cli")]
+#[clap(
+    about = "Generate an index for use with the rippkgs cli",
+    after_help = "Exit codes:
+  1  other errors
+  3  the evaluator (`nix` or `nix-eval-jobs`) was not found
+  4  nix evaluation failed
+  5  the registry or packages.json couldn't be parsed
+  6  evaluation exceeded --timeout
+  7  evaluation exceeded --max-memory"
+)]
 struct Opts {
     #[clap(subcommand)]
     cmd: Subcmd,
+
+    /// How to report progress while building an index. `json` prints one event per line on
+    /// stdout.
+    #[clap(long, global = true, value_enum, default_value_t)]
+    progress: progress::ProgressMode,
 }
 
 #[derive(Debug, Subcommand)]
@@ -28,63 +60,397 @@ enum Subcmd {
     Registry(ImportRegistry),
     /// Generate an index from a nixpkgs expression
     Nixpkgs(IndexNixpkgs),
+    /// Generate an index from the `packages.json` or `packages.json.br` of a NixOS channel,
+    /// without evaluating nixpkgs
+    PackagesJson(ImportPackagesJson),
+    /// Generate an index from the packages of a flake
+    Flake(flake::IndexFlake),
+    /// Upgrade an existing index to the current schema version in place
+    Migrate(MigrateIndex),
+    /// Show the packages that changed between two indexes
+    Diff(diff::DiffIndexes),
 }
 
 #[derive(Debug, Args)]
 struct ImportRegistry {
-    /// The registry to import. This should be generated by calling `genRegistry` from the flake
-    /// library.
+    /// The registry to import, or `-` to read it from stdin. This should be generated by calling
+    /// `genRegistry` from the flake library, or be the output of `nix-env -qaP --json --meta` or
+    /// `nix search --json`. It may be compressed with gzip, zstd or brotli.
     registry: PathBuf,
 
-    /// The location to write the saved index to.
-    #[clap(short, long, default_value = "rippkgs-index.sqlite")]
-    output: PathBuf,
+    /// The format of the registry file.
+    #[clap(long, value_enum, default_value_t)]
+    format: formats::Format,
+
+    #[clap(flatten)]
+    filter: filter::FilterOpts,
+
+    #[clap(flatten)]
+    output: OutputOpts,
+}
+
+#[derive(Debug, Args)]
+struct ImportPackagesJson {
+    /// The `packages.json` to import, plain or brotli-compressed. Channels publish it as
+    /// `packages.json.br`.
+    packages_json: PathBuf,
+
+    #[clap(flatten)]
+    filter: filter::FilterOpts,
+
+    #[clap(flatten)]
+    output: OutputOpts,
 }
 
 #[derive(Debug, Args)]
 struct IndexNixpkgs {
-    /// Optional location to save the generated registry to.
-    #[clap(short = 'r', long)]
-    save_registry: Option<PathBuf>,
+    #[clap(flatten)]
+    eval: EvalOpts,
 
     /// Optional expression to use for the `config` argument to `import <nixpkgs>`.
     #[clap(short = 'a', long, default_value = "{}")]
     nixpkgs_arg: String,
 
+    /// A nixpkgs overlay file to apply, after any overlays in the `--nixpkgs-arg` expression. May
+    /// be given several times, in which case the overlays are applied in order.
+    #[clap(long = "overlay", value_name = "FILE")]
+    overlays: Vec<PathBuf>,
+
     /// The location of Nixpkgs on-disk to index. If omitted, will import `<nixpkgs>` without
     /// passing the `-I` flag to nix.
     nixpkgs: Option<PathBuf>,
 
+    #[clap(flatten)]
+    scopes: scope::ScopeOpts,
+
+    #[clap(flatten)]
+    eval_jobs: eval_jobs::EvalJobsOpts,
+
+    #[clap(flatten)]
+    filter: filter::FilterOpts,
+
+    #[clap(flatten)]
+    output: OutputOpts,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct EvalOpts {
+    /// Optional location to save the generated registry to. It is compressed if the file name
+    /// ends in `.gz`, `.zst` or `.br`.
+    #[clap(short = 'r', long)]
+    save_registry: Option<PathBuf>,
+
+    /// How to compress the saved registry, overriding the file extension.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "COMPRESSION",
+        requires = "save_registry"
+    )]
+    compress: Option<compression::Compression>,
+
+    /// A system to record package availability and store paths for. May be given several times
+    /// to index multiple systems into one index. If omitted, only the current system is indexed.
+    #[clap(long = "system")]
+    systems: Vec<String>,
+
+    /// Optional location to write the full stderr of the nix evaluator to.
+    #[clap(long)]
+    error_log: Option<PathBuf>,
+
+    /// The nix executable to run. Defaults to `nix` from `PATH`.
+    #[clap(long, value_name = "PATH")]
+    nix: Option<String>,
+
+    /// A nix configuration setting to pass to every nix invocation, e.g.
+    /// `--option allow-import-from-derivation false`. May be given several times.
+    #[clap(long = "option", num_args = 2, value_names = ["NAME", "VALUE"])]
+    options: Vec<String>,
+
+    /// An extra entry for the nix search path, e.g. `-I nixpkgs-overlays=./overlays`. May be given
+    /// several times.
+    #[clap(short = 'I', long = "search-path", value_name = "PATH")]
+    search_path: Vec<String>,
+
+    /// The nix store to evaluate against, e.g. `--store /tmp/store` in sandboxed builders.
+    #[clap(long, value_name = "URL")]
+    store: Option<String>,
+
+    /// Kill the evaluator, and everything it started, once the run has taken this many seconds.
+    /// The timeout covers every evaluation of the run, e.g. one for each `--system`.
+    #[clap(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Kill an evaluator process, and everything it started, once they use more than this much
+    /// memory in MiB.
+    #[clap(long, value_name = "MIB")]
+    max_memory: Option<u64>,
+
+    /// When the first evaluation of the run started, which `--timeout` counts from.
+    #[clap(skip)]
+    started: OnceLock<Instant>,
+}
+
+impl EvalOpts {
+    /// The arguments passing the configured settings, search path entries and store to nix.
+    pub fn nix_args(&self) -> Vec<&str> {
+        let mut args = vec![];
+
+        for option in self.options.chunks(2) {
+            args.push("--option");
+            args.extend(option.iter().map(String::as_str));
+        }
+
+        for entry in &self.search_path {
+            args.extend(["-I", entry]);
+        }
+
+        if let Some(store) = &self.store {
+            args.extend(["--store", store]);
+        }
+
+        args
+    }
+
+    /// Run the configured nix with `args`, returning its stdout.
+    pub fn run_nix(&self, args: &[&str]) -> Result<Vec<u8>, nix::EvalError> {
+        let args = [args, &self.nix_args()].concat();
+        nix::run(self.nix(), &args, self.error_log.as_deref(), self.limits())
+    }
+
+    /// Run the configured nix with `args`, passing its stdout to `read` while it is still running.
+    pub fn stream_nix(
+        &self,
+        args: &[&str],
+        read: impl FnOnce(&mut ChildStdout) -> Result<()>,
+    ) -> Result<()> {
+        let args = [args, &self.nix_args()].concat();
+        nix::stream(
+            self.nix(),
+            &args,
+            self.error_log.as_deref(),
+            self.limits(),
+            read,
+        )
+    }
+
+    pub fn limits(&self) -> nix::Limits {
+        nix::Limits {
+            deadline: self.timeout.map(|timeout| nix::Deadline {
+                start: *self.started.get_or_init(Instant::now),
+                timeout: Duration::from_secs(timeout),
+            }),
+            max_memory: self.max_memory,
+        }
+    }
+
+    fn nix(&self) -> &str {
+        self.nix.as_deref().unwrap_or("nix")
+    }
+
+    /// Truncate the error log, which is appended to by every nix invocation of a run.
+    fn reset_error_log(&self) -> Result<()> {
+        if let Some(error_log) = &self.error_log {
+            File::create(error_log).context("creating error log")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct OutputOpts {
     /// The location to write the saved index to.
     #[clap(short, long, default_value = "rippkgs-index.sqlite")]
     output: PathBuf,
+
+    /// Update an existing index in place, only rewriting packages that changed. If the index
+    /// doesn't exist yet, a new one is written.
+    #[clap(short, long)]
+    update: bool,
+}
+
+#[derive(Debug, Args)]
+struct MigrateIndex {
+    /// The index to upgrade.
+    #[clap(default_value = "rippkgs-index.sqlite")]
+    index: PathBuf,
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let opts = Opts::parse();
+    progress::init(opts.progress);
 
-    let output = match &opts.cmd {
-        Subcmd::Registry(opts) => opts.output.as_path(),
-        Subcmd::Nixpkgs(opts) => opts.output.as_path(),
-    };
+    match run(opts) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
 
-    match std::fs::remove_file(output) {
-        Ok(()) => (),
-        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
-        Err(err) => Err(err).context("removing previous index db")?,
+/// The exit code for `err`, distinguishing evaluator failures from other errors.
+fn exit_code(err: &eyre::Report) -> u8 {
+    err.chain()
+        .find_map(|err| err.downcast_ref::<nix::EvalError>())
+        .map_or(1, nix::EvalError::exit_code)
+}
+
+fn run(opts: Opts) -> Result<()> {
+    match &opts.cmd {
+        Subcmd::Registry(opts) => index(
+            &opts.output,
+            &EvalOpts::default(),
+            &opts.filter,
+            eval::RegistryFile(&opts.registry, opts.format),
+        )
+        .context("importing registry"),
+        Subcmd::PackagesJson(opts) => index(
+            &opts.output,
+            &EvalOpts::default(),
+            &opts.filter,
+            packages_json::PackagesJson(&opts.packages_json),
+        )
+        .context("importing packages.json"),
+        Subcmd::Nixpkgs(opts) => index(
+            &opts.output,
+            &opts.eval,
+            &opts.filter,
+            NixpkgsEvaluator(opts),
+        )
+        .context("indexing nixpkgs"),
+        Subcmd::Flake(opts) => index(&opts.output, &opts.eval, &opts.filter, opts.evaluator())
+            .context("indexing flake"),
+        Subcmd::Migrate(opts) => migrate_index(opts).context("migrating index"),
+        Subcmd::Diff(opts) => diff::diff(opts).context("comparing indexes"),
+    }
+}
+
+/// Write the packages produced by `evaluator` that pass `filter` to the output index.
+fn index(
+    output: &OutputOpts,
+    eval: &EvalOpts,
+    filter: &filter::FilterOpts,
+    mut evaluator: impl Evaluator,
+) -> Result<()> {
+    let mut filter = filter.filter().context("reading filters")?;
+    generate_index(output, &mut filter, |sink| {
+        eval::evaluate(eval, &mut evaluator, sink)
+    })
+}
+
+/// Write the registry entries that `load` passes to its sink and `filter` keeps to the output
+/// index, holding the index lock for the whole run. Entries are written as they are produced, so
+/// the registry never has to be held in memory.
+fn generate_index(
+    OutputOpts { output, update }: &OutputOpts,
+    filter: &mut Filter,
+    load: impl FnOnce(&mut Sink) -> Result<IndexMetadata>,
+) -> Result<()> {
+    let _lock = lock_index(output).context("locking index")?;
+
+    if *update && output.exists() {
+        update_index(output, filter, load).context("updating index")?;
+        return Ok(());
     }
 
-    let registry = match &opts.cmd {
-        Subcmd::Registry(opts) => import_registry(opts).context("importing registry")?,
-        Subcmd::Nixpkgs(opts) => index_nixpkgs(opts).context("indexing nixpkgs")?,
+    // Build the new index beside the old one so readers keep seeing the previous index until the
+    // new one is complete, and so a failed run leaves the previous index in place.
+    let dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     };
+    let tmp = tempfile::Builder::new()
+        .prefix(".rippkgs-index")
+        .suffix(".sqlite")
+        .permissions(Permissions::from_mode(0o644))
+        .tempfile_in(dir)
+        .context("creating temporary index file")?;
+
+    write_index(tmp.path(), filter, load).context("writing index")?;
+    validate_index(tmp.path()).context("validating index")?;
+
+    tmp.persist(output).context("replacing previous index")?;
+
+    Ok(())
+}
+
+/// Take an advisory lock on `<index>.lock`, held until the returned file is dropped, so that
+/// concurrent runs don't race on the same output.
+fn lock_index(index: &Path) -> Result<File> {
+    let mut lock_path = index.as_os_str().to_owned();
+    lock_path.push(".lock");
+
+    let lock = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("opening lock file {}", Path::new(&lock_path).display()))?;
+
+    // released when the file is closed
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(lock);
+    }
+
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        eyre::bail!(
+            "another rippkgs-index is already writing to {}",
+            index.display()
+        );
+    }
+    Err(err).context("acquiring lock")
+}
+
+/// Check that a freshly written index is readable and complete before it replaces the previous
+/// one.
+fn validate_index(index: &Path) -> Result<()> {
+    let conn = rusqlite::Connection::open_with_flags(
+        index,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .context("connecting to index database")?;
+
+    check_index(&conn)
+}
+
+/// The checks of [`validate_index`], which are run on an updated index before its transaction is
+/// committed.
+fn check_index(conn: &rusqlite::Connection) -> Result<()> {
+    let integrity: String = conn
+        .query_row("PRAGMA quick_check", [], |r| r.get(0))
+        .context("checking database integrity")?;
+    if integrity != "ok" {
+        eyre::bail!("index database is corrupt: {integrity}");
+    }
 
-    write_index(output, registry).context("writing index")?;
+    let metadata = IndexMetadata::read(conn).context("reading index metadata")?;
+    if metadata.schema_version != rippkgs::SCHEMA_VERSION {
+        eyre::bail!(
+            "index has schema version {}, expected {}",
+            metadata.schema_version,
+            rippkgs::SCHEMA_VERSION
+        );
+    }
+
+    let package_count = count_packages(conn)?;
+    if package_count != metadata.package_count {
+        eyre::bail!(
+            "index has {package_count} packages, expected {}",
+            metadata.package_count
+        );
+    }
 
     Ok(())
 }
 
-fn write_index(index: &Path, registry: Registry) -> Result<()> {
+fn write_index(
+    index: &Path,
+    filter: &mut Filter,
+    load: impl FnOnce(&mut Sink) -> Result<IndexMetadata>,
+) -> Result<()> {
     let mut conn = rusqlite::Connection::open_with_flags(
         index,
         OpenFlags::SQLITE_OPEN_CREATE
@@ -95,94 +461,468 @@ fn write_index(index: &Path, registry: Registry) -> Result<()> {
 
     conn.execute(Package::create_table(), [])
         .context("creating table in database")?;
+    conn.execute(IndexMetadata::create_table(), [])
+        .context("creating metadata table in database")?;
+    conn.execute(Exclusion::create_table(), [])
+        .context("creating exclusion table in database")?;
+    conn.execute(Package::create_search_table(), [])
+        .context("creating search table in database")?;
+    conn.execute(Package::create_candidate_table(), [])
+        .context("creating candidate table in database")?;
+    rippkgs::set_schema_version(&conn).context("setting schema version")?;
 
     let start = Instant::now();
     let tx = conn.transaction().context("starting transaction")?;
 
-    {
+    let mut package_count = 0;
+    let mut systems = BTreeSet::new();
+
+    let mut metadata = {
         let mut create_row_query = tx
-            .prepare(
-                r#"
-    INSERT INTO packages (attribute, name, version, storePaths, propagatedBuildInputs, propagatedNativeBuildInputs, description, long_description)
-    VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-                "#,
-            )
+            .prepare(INSERT_PACKAGE)
             .context("preparing INSERT query")?;
+        let mut exclude_query = tx
+            .prepare(INSERT_EXCLUSION)
+            .context("preparing INSERT query")?;
+
+        load(&mut |attr, mut info| {
+            // entries `genRegistry` left out aren't packages, so the filters don't count them
+            if let Some(reason) = info.excluded.take().or_else(|| filter.exclusion(&attr)) {
+                return insert_exclusion(&mut exclude_query, &attr, &reason);
+            }
 
-        registry
-            .into_iter()
-            .map(|(attr, info)| info.into_rippkgs_package(attr))
-            .try_for_each(
-                |Package {
-                     attribute,
-                     name,
-                     version,
-                     store_paths,
-                     propagated_build_inputs,
-                     propagated_native_build_inputs,
-                     description,
-                     long_description,
-                     score: _score, // score not included in the database
-                     ..
-                 }| {
-                    let store_paths = store_paths
-                        .map(|store_paths| serde_json::to_string(&store_paths))
-                        .transpose()?;
-                    let propagated_build_inputs = propagated_build_inputs
-                        .map(|propagated_build_inputs| {
-                            serde_json::to_string(&propagated_build_inputs)
-                        })
-                        .transpose()?;
-                    let propagated_native_build_inputs = propagated_native_build_inputs
-                        .map(|propagated_native_build_inputs| {
-                            serde_json::to_string(&propagated_native_build_inputs)
-                        })
-                        .transpose()?;
-                    create_row_query
-                        .execute(rusqlite::params![
-                            attribute,
-                            name,
-                            version,
-                            store_paths,
-                            propagated_build_inputs,
-                            propagated_native_build_inputs,
-                            description,
-                            long_description
-                        ])
-                        .context("inserting package into database")
-                        .map(|_| ())
-                },
-            )?;
+            package_count += 1;
+            systems.extend(info.systems.iter().flat_map(|s| s.keys().cloned()));
+
+            insert_package(&mut create_row_query, info.into_rippkgs_package(attr))?;
+            progress::writing(package_count, false);
+
+            Ok(())
+        })?
+    };
+    progress::writing(package_count, true);
+    filter.report();
+
+    Package::rebuild_search_table(&tx).context("building search table")?;
+    Package::rebuild_candidates(&tx).context("finding aliases")?;
+
+    // registries may list an attribute twice, with the later entry replacing the earlier one
+    metadata.package_count = count_packages(&tx)?;
+    if metadata.systems.is_none() && !systems.is_empty() {
+        metadata.systems = Some(systems.into_iter().collect());
     }
+    metadata
+        .write(&tx)
+        .context("writing metadata into database")?;
 
     tx.commit().context("committing database")?;
 
-    println!(
-        "wrote index in {:.4} seconds",
-        start.elapsed().as_secs_f64()
+    progress::report(
+        Event::Finished {
+            step: "wrote index",
+            seconds: start.elapsed().as_secs_f64(),
+        },
+        true,
     );
 
     Ok(())
 }
 
-fn index_nixpkgs(
-    IndexNixpkgs {
-        save_registry,
-        nixpkgs_arg,
+fn count_packages(conn: &rusqlite::Connection) -> Result<u64> {
+    conn.query_row("SELECT COUNT(*) FROM packages", [], |r| r.get(0))
+        .context("counting packages")
+}
+
+const INSERT_EXCLUSION: &str = r#"
+    INSERT OR REPLACE INTO excluded (attribute, reason)
+    VALUES (?, ?)
+"#;
+
+fn insert_exclusion(query: &mut rusqlite::Statement, attribute: &str, reason: &str) -> Result<()> {
+    query
+        .execute(rusqlite::params![attribute, reason])
+        .context("inserting exclusion into database")
+        .map(|_| ())
+}
+
+const INSERT_PACKAGE: &str = r#"
+    INSERT OR REPLACE INTO packages (attribute, name, version, storePaths, propagatedBuildInputs, propagatedNativeBuildInputs, description, long_description, homepage, license, broken, insecure, unfree, unsupported, systems)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+"#;
+
+fn insert_package(
+    query: &mut rusqlite::Statement,
+    Package {
+        attribute,
+        name,
+        version,
+        store_paths,
+        propagated_build_inputs,
+        propagated_native_build_inputs,
+        description,
+        long_description,
+        homepage,
+        license,
+        broken,
+        insecure,
+        unfree,
+        unsupported,
+        systems,
+        score: _score, // score not included in the database
+        ..
+    }: Package,
+) -> Result<()> {
+    let store_paths = store_paths
+        .map(|store_paths| serde_json::to_string(&store_paths))
+        .transpose()?;
+    let propagated_build_inputs = propagated_build_inputs
+        .map(|propagated_build_inputs| serde_json::to_string(&propagated_build_inputs))
+        .transpose()?;
+    let propagated_native_build_inputs = propagated_native_build_inputs
+        .map(|propagated_native_build_inputs| {
+            serde_json::to_string(&propagated_native_build_inputs)
+        })
+        .transpose()?;
+    let homepage = homepage
+        .map(|homepage| serde_json::to_string(&homepage))
+        .transpose()?;
+    let license = license
+        .map(|license| serde_json::to_string(&license))
+        .transpose()?;
+    let systems = systems
+        .map(|systems| serde_json::to_string(&systems))
+        .transpose()?;
+
+    query
+        .execute(rusqlite::params![
+            attribute,
+            name,
+            version,
+            store_paths,
+            propagated_build_inputs,
+            propagated_native_build_inputs,
+            description,
+            long_description,
+            homepage,
+            license,
+            broken,
+            insecure,
+            unfree,
+            unsupported,
+            systems
+        ])
+        .context("inserting package into database")
+        .map(|_| ())
+}
+
+/// How many packages [`update_index`] changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UpdateCounts {
+    inserted: u64,
+    updated: u64,
+    deleted: u64,
+    unchanged: u64,
+}
+
+/// Apply the differences between an existing index and a new registry in place, leaving rows for
+/// unchanged packages untouched.
+fn update_index(
+    index: &Path,
+    filter: &mut Filter,
+    load: impl FnOnce(&mut Sink) -> Result<IndexMetadata>,
+) -> Result<UpdateCounts> {
+    let mut conn = rusqlite::Connection::open_with_flags(
+        index,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .context("connecting to index database")?;
+
+    let from = rippkgs::migrate(&mut conn).context("migrating index")?;
+    if from > rippkgs::SCHEMA_VERSION {
+        eyre::bail!(
+            "index has schema version {from}, which is newer than the version supported by this \
+            rippkgs-index ({}). Please upgrade rippkgs-index.",
+            rippkgs::SCHEMA_VERSION,
+        );
+    }
+
+    let start = Instant::now();
+    let tx = conn.transaction().context("starting transaction")?;
+
+    let mut existing = tx
+        .prepare("SELECT * FROM packages")
+        .context("preparing SELECT query")?
+        .query_map([], |r| Package::try_from(r))
+        .context("reading existing packages")?
+        .map(|package| package.map(|package| (package.attribute.clone(), package)))
+        .collect::<Result<HashMap<_, _>, _>>()
+        .context("reading existing packages")?;
+
+    let (mut inserted, mut updated, mut unchanged) = (0, 0, 0);
+    let mut systems = BTreeSet::new();
+
+    // exclusions are cheap to rewrite, so they are replaced wholesale
+    tx.execute("DELETE FROM excluded", [])
+        .context("clearing exclusions")?;
+
+    let mut metadata = {
+        let mut create_row_query = tx
+            .prepare(INSERT_PACKAGE)
+            .context("preparing INSERT query")?;
+        let mut exclude_query = tx
+            .prepare(INSERT_EXCLUSION)
+            .context("preparing INSERT query")?;
+
+        let metadata = load(&mut |attr, mut info| {
+            // a package that is now excluded or filtered out is left in `existing`, and deleted
+            // below
+            if let Some(reason) = info.excluded.take().or_else(|| filter.exclusion(&attr)) {
+                return insert_exclusion(&mut exclude_query, &attr, &reason);
+            }
+
+            systems.extend(info.systems.iter().flat_map(|s| s.keys().cloned()));
+            let package = info.into_rippkgs_package(attr);
+
+            let changed = match existing.remove(&package.attribute) {
+                None => {
+                    inserted += 1;
+                    true
+                }
+                Some(previous) if previous == package => {
+                    unchanged += 1;
+                    false
+                }
+                Some(_) => {
+                    updated += 1;
+                    true
+                }
+            };
+
+            if changed {
+                let attribute = package.attribute.clone();
+                Package::remove_from_search_table(&tx, &attribute)
+                    .context("updating search table")?;
+                insert_package(&mut create_row_query, package)?;
+                Package::add_to_search_table(&tx, &attribute).context("updating search table")?;
+            }
+            progress::writing(inserted + updated, false);
+
+            Ok(())
+        })?;
+        progress::writing(inserted + updated, true);
+        filter.report();
+
+        // anything left over is no longer in the registry
+        let mut delete_row_query = tx
+            .prepare("DELETE FROM packages WHERE attribute = ?1")
+            .context("preparing DELETE query")?;
+
+        for attribute in existing.keys() {
+            Package::remove_from_search_table(&tx, attribute).context("updating search table")?;
+            delete_row_query
+                .execute(rusqlite::params![attribute])
+                .context("deleting package from database")?;
+        }
+
+        Package::rebuild_candidates(&tx).context("finding aliases")?;
+
+        metadata
+    };
+
+    metadata.package_count = count_packages(&tx)?;
+    if metadata.systems.is_none() && !systems.is_empty() {
+        metadata.systems = Some(systems.into_iter().collect());
+    }
+    metadata
+        .write(&tx)
+        .context("writing metadata into database")?;
+
+    check_index(&tx).context("validating index")?;
+    tx.commit().context("committing database")?;
+
+    let counts = UpdateCounts {
+        inserted,
+        updated,
+        deleted: existing.len() as u64,
+        unchanged,
+    };
+    progress::report(
+        Event::Updated {
+            seconds: start.elapsed().as_secs_f64(),
+            inserted: counts.inserted,
+            updated: counts.updated,
+            deleted: counts.deleted,
+            unchanged: counts.unchanged,
+        },
+        true,
+    );
+
+    Ok(counts)
+}
+
+fn migrate_index(MigrateIndex { index }: &MigrateIndex) -> Result<()> {
+    // checked before locking, so a mistyped path doesn't leave a lock file behind
+    if !index.is_file() {
+        eyre::bail!("no index at {}", index.display());
+    }
+    let _lock = lock_index(index).context("locking index")?;
+
+    let mut conn = rusqlite::Connection::open_with_flags(
+        index,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .context("connecting to index database")?;
+
+    let from = rippkgs::migrate(&mut conn).context("applying migrations")?;
+
+    if from > rippkgs::SCHEMA_VERSION {
+        eyre::bail!(
+            "index has schema version {from}, which is newer than the version supported by this \
+            rippkgs-index ({}). Please upgrade rippkgs-index.",
+            rippkgs::SCHEMA_VERSION,
+        );
+    } else if from == rippkgs::SCHEMA_VERSION {
+        println!("index is already at schema version {from}");
+    } else {
+        println!(
+            "migrated index from schema version {from} to {}",
+            rippkgs::SCHEMA_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+/// Evaluates nixpkgs with the nix CLI.
+struct NixpkgsEvaluator<'a>(&'a IndexNixpkgs);
+
+impl Evaluator for NixpkgsEvaluator<'_> {
+    fn metadata(&mut self) -> Result<IndexMetadata> {
+        nixpkgs_metadata(self.0)
+    }
+
+    fn registry(&mut self, system: Option<&str>, sink: &mut Sink) -> Result<()> {
+        let opts = self.0;
+        if !opts.eval_jobs.eval_jobs {
+            return eval_registry(opts, system, sink);
+        }
+
+        let mut args = vec![];
+
+        let nixpkgs_include_arg = nixpkgs_include_arg(opts.nixpkgs.as_deref());
+        if let Some(include_arg) = nixpkgs_include_arg.as_ref() {
+            args.push("-I");
+            args.push(include_arg.as_str());
+        }
+
+        eval_jobs::eval_registry(
+            &opts.eval,
+            &opts.eval_jobs,
+            &opts.scopes,
+            &opts.import_arg(system)?,
+            system,
+            &args,
+            sink,
+        )
+    }
+}
+
+impl IndexNixpkgs {
+    /// The absolute paths of the overlay files, which nix needs to import them from anywhere.
+    fn overlay_paths(&self) -> Result<Vec<PathBuf>> {
+        self.overlays
+            .iter()
+            .map(|overlay| {
+                overlay
+                    .canonicalize()
+                    .with_context(|| format!("finding overlay {}", overlay.display()))
+            })
+            .collect()
+    }
+
+    /// The `import <nixpkgs>` argument, with the overlays appended and the local system overridden
+    /// to `system` if given.
+    fn import_arg(&self, system: Option<&str>) -> Result<String> {
+        let overlays = self.overlay_paths()?;
+        let nixpkgs_arg = if overlays.is_empty() {
+            self.nixpkgs_arg.clone()
+        } else {
+            let imports = overlays
+                .iter()
+                .map(|overlay| format!(" (import {})", nix_string(&overlay.display().to_string())))
+                .collect::<String>();
+
+            format!(
+                "let arg = ({}); in arg // {{ overlays = (arg.overlays or [ ]) ++ [{imports} ]; }}",
+                self.nixpkgs_arg
+            )
+        };
+
+        Ok(system_nixpkgs_arg(&nixpkgs_arg, system))
+    }
+}
+
+/// `s` as a nix string literal.
+fn nix_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace("${", r"\${");
+    format!(r#""{escaped}""#)
+}
+
+/// The nixpkgs import argument, with the local system overridden to `system` if given.
+fn system_nixpkgs_arg(nixpkgs_arg: &str, system: Option<&str>) -> String {
+    match system {
+        Some(system) => {
+            let system = nix_string(system);
+            format!("({nixpkgs_arg}) // {{ localSystem = {{ system = {system}; }}; }}")
+        }
+        None => nixpkgs_arg.to_string(),
+    }
+}
+
+/// Evaluate `genRegistry` for nixpkgs, optionally for a `system` other than the current one.
+fn eval_registry(opts: &IndexNixpkgs, system: Option<&str>, sink: &mut Sink) -> Result<()> {
+    let IndexNixpkgs {
+        eval,
         nixpkgs,
+        scopes,
         ..
-    }: &IndexNixpkgs,
-) -> Result<Registry> {
+    } = opts;
+
+    let nixpkgs_arg = opts.import_arg(system)?;
+    let scope_args = scopes.gen_registry_args();
+
     let apply_arg = format!(
         r#"
 genRegistry:
 
-let pkgs = import <nixpkgs> {nixpkgs_arg};
-    genRegistry' = genRegistry pkgs;
+let pkgs = import <nixpkgs> ({nixpkgs_arg});
+    genRegistry' = genRegistry {{ inherit (pkgs) lib; {scope_args} }};
 in genRegistry' pkgs
         "#,
     );
 
+    let mut args = vec![];
+
+    let nixpkgs_include_arg = nixpkgs_include_arg(nixpkgs.as_deref());
+    if let Some(include_arg) = nixpkgs_include_arg.as_ref() {
+        args.push("-I");
+        args.push(include_arg.as_str());
+    }
+
+    run_gen_registry(eval, &apply_arg, &args, sink)
+}
+
+/// Run `nix eval` on `genRegistry.nix`, applying `apply_arg` to it, and stream the resulting
+/// registry to `sink`.
+fn run_gen_registry(
+    eval: &EvalOpts,
+    apply_arg: &str,
+    extra_args: &[&str],
+    sink: &mut Sink,
+) -> Result<()> {
     let mut args = vec![
         "eval",
         "--impure",
@@ -190,70 +930,768 @@ in genRegistry' pkgs
         "--expr",
         include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/lib/genRegistry.nix")),
         "--apply",
-        apply_arg.as_str(),
+        apply_arg,
     ];
+    args.extend_from_slice(extra_args);
+
+    let start = Instant::now();
+
+    eval.stream_nix(&args, |stdout| {
+        registry::stream(BufReader::new(stdout), sink)
+    })
+    .context("running `nix eval`")?;
+
+    progress::report(
+        Event::Finished {
+            step: "evaluated registry",
+            seconds: start.elapsed().as_secs_f64(),
+        },
+        true,
+    );
+
+    Ok(())
+}
+
+fn nixpkgs_include_arg(nixpkgs: Option<&Path>) -> Option<String> {
+    nixpkgs.map(|nixpkgs| format!("nixpkgs={}", nixpkgs.display()))
+}
+
+/// Evaluate information about the nixpkgs being indexed. This only imports `<nixpkgs/lib>`, so it
+/// is cheap compared to generating the registry.
+fn nixpkgs_metadata(opts: &IndexNixpkgs) -> Result<IndexMetadata> {
+    let IndexNixpkgs {
+        eval,
+        nixpkgs_arg,
+        nixpkgs,
+        ..
+    } = opts;
 
-    let mut nixpkgs_include_arg = String::new();
-    if let Some(nixpkgs) = nixpkgs.as_ref() {
-        nixpkgs_include_arg.push_str(format!("nixpkgs={}", nixpkgs.display()).as_str());
+    #[derive(Deserialize)]
+    struct NixpkgsInfo {
+        source: String,
+        revision: Option<String>,
+        system: String,
+    }
+
+    let expr = r#"
+let lib = import <nixpkgs/lib>;
+in {
+  source = toString <nixpkgs>;
+  revision = lib.trivial.revisionWithDefault null;
+  system = builtins.currentSystem;
+}
+    "#;
+
+    let mut args = vec!["eval", "--impure", "--json", "--expr", expr];
+
+    let nixpkgs_include_arg = nixpkgs_include_arg(nixpkgs.as_deref());
+    if let Some(include_arg) = nixpkgs_include_arg.as_ref() {
         args.push("-I");
-        args.push(nixpkgs_include_arg.as_str());
+        args.push(include_arg.as_str());
     }
 
-    let start = Instant::now();
+    let overlays = opts.overlay_paths()?;
+    let output = eval.run_nix(&args).context("evaluating nixpkgs metadata")?;
 
-    let output = Command::new("nix")
-        .args(args.iter())
-        .output()
-        .with_context(|| format!("getting nixpkgs packages from {}", "nixpkgs"))?;
+    let NixpkgsInfo {
+        source,
+        revision,
+        system,
+    } = serde_json::from_slice(&output)
+        .map_err(nix::EvalError::Json)
+        .context("reading nixpkgs metadata JSON")?;
 
-    println!(
-        "evaluated registry in {:.4} seconds",
-        start.elapsed().as_secs_f64()
-    );
+    Ok(IndexMetadata {
+        source: Some(source),
+        revision,
+        nixpkgs_arg: Some(nixpkgs_arg.clone()),
+        overlays: (!overlays.is_empty()).then(|| {
+            overlays
+                .iter()
+                .map(|overlay| overlay.display().to_string())
+                .collect()
+        }),
+        system: Some(system),
+        systems: (!eval.systems.is_empty()).then(|| eval.systems.clone()),
+        ..IndexMetadata::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        path::Path,
+    };
+
+    use clap::Parser;
+    use rippkgs::{Exclusion, IndexMetadata, Package};
+
+    use crate::{
+        data::PackageInfo,
+        eval::{Fake, RegistryFile},
+        filter::FilterOpts,
+        formats::Format,
+        nix::EvalError,
+        EvalOpts, Opts, OutputOpts, Subcmd,
+    };
 
-    if !output.status.success() {
-        panic!(
-            "`nix eval` failed: {}",
-            String::from_utf8_lossy(&output.stderr)
+    fn registry(json: serde_json::Value) -> BTreeMap<String, PackageInfo> {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn fake(
+        registries: impl IntoIterator<
+            Item = (
+                Option<&'static str>,
+                Result<BTreeMap<String, PackageInfo>, EvalError>,
+            ),
+        >,
+    ) -> Fake {
+        Fake {
+            metadata: IndexMetadata {
+                source: Some("fake".to_string()),
+                ..IndexMetadata::new()
+            },
+            registries: registries
+                .into_iter()
+                .map(|(system, registry)| (system.map(str::to_string), registry))
+                .collect(),
+        }
+    }
+
+    /// Write a shell script named `name` into `dir` to stand in for an evaluator. It appends its
+    /// arguments, one per line, to `<name>.args` in `dir` and then runs `body`.
+    pub fn fake_nix(dir: &Path, name: &str, body: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join(name);
+        let log = dir.join(format!("{name}.args"));
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$@\" >> '{}'\n{body}\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        path.display().to_string()
+    }
+
+    /// The arguments the evaluator written by [`fake_nix`] was run with so far.
+    pub fn fake_nix_args(dir: &Path, name: &str) -> String {
+        std::fs::read_to_string(dir.join(format!("{name}.args"))).unwrap_or_default()
+    }
+
+    pub fn read_index(index: &Path) -> (HashMap<String, Package>, IndexMetadata) {
+        let conn = rusqlite::Connection::open(index).unwrap();
+        let packages = conn
+            .prepare("SELECT * FROM packages")
+            .unwrap()
+            .query_map([], |r| Package::try_from(r))
+            .unwrap()
+            .map(|package| package.map(|package| (package.attribute.clone(), package)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        (packages, IndexMetadata::read(&conn).unwrap())
+    }
+
+    #[test]
+    fn index_fake_registry() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+        let eval = EvalOpts {
+            save_registry: Some(dir.path().join("registry.json")),
+            ..EvalOpts::default()
+        };
+
+        let hello = registry(serde_json::json!({
+            "hello": { "pname": "hello", "version": "2.12", "storePaths": { "out": "/nix/store/x" } },
+            "vim": { "pname": "vim", "version": "9.0" },
+        }));
+        super::index(
+            &output,
+            &eval,
+            &FilterOpts::default(),
+            fake([(None, Ok(hello))]),
+        )
+        .unwrap();
+
+        let (packages, metadata) = read_index(&output.output);
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages["hello"].version.as_deref(), Some("2.12"));
+        assert_eq!(metadata.source.as_deref(), Some("fake"));
+        assert_eq!(metadata.package_count, 2);
+
+        // the saved registry imports to the same index
+        let imported = OutputOpts {
+            output: dir.path().join("imported.sqlite"),
+            update: false,
+        };
+        super::index(
+            &imported,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            RegistryFile(&dir.path().join("registry.json"), Format::Auto),
+        )
+        .unwrap();
+        assert_eq!(read_index(&imported.output).0, packages);
+    }
+
+    #[test]
+    fn index_fake_systems() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+        let eval = EvalOpts {
+            systems: vec!["x86_64-linux".to_string(), "aarch64-darwin".to_string()],
+            ..EvalOpts::default()
+        };
+
+        let evaluator = fake([
+            (
+                Some("x86_64-linux"),
+                Ok(registry(serde_json::json!({
+                    "hello": { "storePaths": { "out": "x86" } },
+                }))),
+            ),
+            (
+                Some("aarch64-darwin"),
+                Ok(registry(serde_json::json!({
+                    "hello": { "storePaths": { "out": "darwin" } },
+                    "darwin-only": {},
+                }))),
+            ),
+        ]);
+        super::index(&output, &eval, &FilterOpts::default(), evaluator).unwrap();
+
+        let (packages, metadata) = read_index(&output.output);
+        assert!(packages["hello"].available_on("x86_64-linux"));
+        assert!(!packages["darwin-only"].available_on("x86_64-linux"));
+        assert_eq!(
+            metadata.systems,
+            Some(vec![
+                "aarch64-darwin".to_string(),
+                "x86_64-linux".to_string()
+            ])
         );
     }
 
-    if let Some(registry) = save_registry {
-        File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(registry)
-            .context("opening registry file")?
-            .write(&output.stdout)
-            .context("writing registry file")?;
+    #[test]
+    fn index_fake_single_system() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+        let eval = EvalOpts {
+            systems: vec!["x86_64-linux".to_string()],
+            ..EvalOpts::default()
+        };
+
+        let evaluator = fake([(
+            Some("x86_64-linux"),
+            Ok(registry(serde_json::json!({
+                "hello": { "storePaths": { "out": "x86" } },
+                "broken": { "excluded": "failed eval" },
+            }))),
+        )]);
+        super::index(&output, &eval, &FilterOpts::default(), evaluator).unwrap();
+
+        let (packages, metadata) = read_index(&output.output);
+        assert!(packages["hello"].available_on("x86_64-linux"));
+        assert!(!packages["hello"].available_on("aarch64-darwin"));
+        assert!(!packages.contains_key("broken"));
+        assert_eq!(metadata.systems, Some(vec!["x86_64-linux".to_string()]));
     }
 
-    let start = Instant::now();
-    let res = serde_json::from_slice(&output.stdout).context("reading registry JSON");
-    println!(
-        "parsed registry in {:.4} seconds",
-        start.elapsed().as_secs_f64()
-    );
+    #[test]
+    fn nix_args() {
+        let Subcmd::Nixpkgs(opts) = Opts::try_parse_from([
+            "rippkgs-index",
+            "nixpkgs",
+            "--option",
+            "allow-import-from-derivation",
+            "false",
+            "-I",
+            "nixpkgs-overlays=./overlays",
+            "--store",
+            "/tmp/store",
+            "--option",
+            "cores",
+            "4",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            unreachable!()
+        };
 
-    res
-}
+        assert_eq!(
+            opts.eval.nix_args(),
+            [
+                "--option",
+                "allow-import-from-derivation",
+                "false",
+                "--option",
+                "cores",
+                "4",
+                "-I",
+                "nixpkgs-overlays=./overlays",
+                "--store",
+                "/tmp/store",
+            ]
+        );
+        assert_eq!(EvalOpts::default().nix_args(), Vec::<&str>::new());
+    }
 
-fn import_registry(ImportRegistry { registry, .. }: &ImportRegistry) -> Result<Registry> {
-    let f = File::options()
-        .read(true)
-        .open(registry)
-        .context("opening registry file")?;
+    #[test]
+    fn system_nixpkgs_arg() {
+        assert_eq!(super::system_nixpkgs_arg("{ }", None), "{ }");
+        assert_eq!(
+            super::system_nixpkgs_arg("{ }", Some(r#"x"${y}"#)),
+            r#"({ }) // { localSystem = { system = "x\"\${y}"; }; }"#
+        );
+    }
 
-    let start = Instant::now();
-    let res = serde_json::from_reader::<_, HashMap<String, PackageInfo>>(f)
-        .context("reading registry JSON")?;
+    #[test]
+    fn import_arg() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        std::fs::write(dir.join("a.nix"), "final: prev: { }").unwrap();
+        std::fs::write(dir.join("b.nix"), "final: prev: { }").unwrap();
+        let parse = |args: &[&str]| {
+            let args = [["rippkgs-index", "nixpkgs"].as_slice(), args].concat();
+            let Subcmd::Nixpkgs(opts) = Opts::try_parse_from(args).unwrap().cmd else {
+                unreachable!()
+            };
+            opts
+        };
 
-    println!(
-        "parsed registry in {:.4} seconds",
-        start.elapsed().as_secs_f64()
-    );
+        let plain = parse(&["--nixpkgs-arg", "{ config = { }; }"]);
+        assert_eq!(plain.import_arg(None).unwrap(), "{ config = { }; }");
+
+        // overlays are imported by absolute path, after the argument's own overlays
+        let a = dir.join("a.nix");
+        let b = dir.join("b.nix");
+        let overlays = parse(&[
+            "--overlay",
+            a.to_str().unwrap(),
+            "--overlay",
+            b.to_str().unwrap(),
+        ]);
+        let with_overlays = format!(
+            "let arg = ({{}}); in arg // {{ overlays = (arg.overlays or [ ]) ++ [ (import \"{}\") \
+             (import \"{}\") ]; }}",
+            a.display(),
+            b.display(),
+        );
+        assert_eq!(overlays.import_arg(None).unwrap(), with_overlays);
+        assert_eq!(
+            overlays.import_arg(Some("aarch64-linux")).unwrap(),
+            format!("({with_overlays}) // {{ localSystem = {{ system = \"aarch64-linux\"; }}; }}")
+        );
+
+        let missing = parse(&["--overlay", dir.join("missing.nix").to_str().unwrap()]);
+        assert!(missing.import_arg(None).is_err());
+    }
+
+    #[test]
+    fn index_fake_exclusions() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+
+        let before = registry(serde_json::json!({
+            "hello": { "meta": { "description": "greeting" } },
+            "broken": { "excluded": "failed eval" },
+        }));
+        super::index(
+            &output,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            fake([(None, Ok(before))]),
+        )
+        .unwrap();
+
+        let (packages, metadata) = read_index(&output.output);
+        assert!(!packages.contains_key("broken"));
+        assert_eq!(metadata.package_count, 1);
+
+        let conn = rusqlite::Connection::open(&output.output).unwrap();
+        let broken = Exclusion::find(&conn, "broken").unwrap().unwrap();
+        assert_eq!(broken.reason, "failed eval");
+        drop(conn);
+
+        // updating replaces the exclusions, and removes packages that became excluded
+        let update = OutputOpts {
+            update: true,
+            ..output
+        };
+        let after = registry(serde_json::json!({
+            "hello": { "excluded": "not available on x86_64-linux" },
+            "broken": { "meta": { "description": "fixed" } },
+        }));
+        super::index(
+            &update,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            fake([(None, Ok(after))]),
+        )
+        .unwrap();
+
+        let (packages, _) = read_index(&update.output);
+        assert!(!packages.contains_key("hello"));
+        assert!(packages.contains_key("broken"));
+
+        let conn = rusqlite::Connection::open(&update.output).unwrap();
+        assert!(Exclusion::find(&conn, "broken").unwrap().is_none());
+        assert_eq!(
+            Exclusion::find(&conn, "hello").unwrap().unwrap().reason,
+            "not available on x86_64-linux"
+        );
+
+        // the search table follows the changed packages
+        conn.execute(
+            "INSERT INTO packages_fts (packages_fts) VALUES ('integrity-check')",
+            [],
+        )
+        .unwrap();
+        let matches = |word: &str| -> Vec<String> {
+            conn.prepare("SELECT attribute FROM packages_fts WHERE packages_fts MATCH ?1")
+                .unwrap()
+                .query_map([word], |r| r.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        assert!(matches("greeting").is_empty());
+        assert_eq!(matches("fixed"), ["broken"]);
+    }
+
+    #[test]
+    fn index_fake_filtered() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+        let Subcmd::Nixpkgs(opts) = Opts::try_parse_from([
+            "rippkgs-index",
+            "nixpkgs",
+            "--include",
+            "hello*",
+            "--exclude",
+            "hello-*",
+        ])
+        .unwrap()
+        .cmd
+        else {
+            unreachable!()
+        };
+
+        let packages = registry(serde_json::json!({
+            "hello": {},
+            "hello-unwrapped": {},
+            "zsh": {},
+            "lib": { "excluded": "not a derivation" },
+        }));
+        super::index(
+            &output,
+            &EvalOpts::default(),
+            &opts.filter,
+            fake([(None, Ok(packages))]),
+        )
+        .unwrap();
 
-    Ok(res)
+        let (packages, metadata) = read_index(&output.output);
+        assert_eq!(packages.keys().collect::<Vec<_>>(), ["hello"]);
+        assert_eq!(metadata.package_count, 1);
+
+        // filtered attributes are recorded with the rule that removed them
+        let conn = rusqlite::Connection::open(&output.output).unwrap();
+        let reason = |attr| Exclusion::find(&conn, attr).unwrap().unwrap().reason;
+        assert_eq!(reason("hello-unwrapped"), "filtered by --exclude hello-*");
+        assert_eq!(reason("zsh"), "filtered by --include");
+        assert_eq!(reason("lib"), "not a derivation");
+    }
+
+    #[test]
+    fn eval_jobs_matches_nix_eval() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // the registry `genRegistry` produces for nixpkgs as configured
+        let entries = serde_json::json!({
+            "hello": { "pname": "hello", "version": "2.12", "storePaths": { "out": "x-hello" } },
+            "unfree": {
+                "pname": "unfree",
+                "storePaths": { "out": "<broken>" },
+                "meta": { "unfree": true },
+            },
+            "python3Packages.requests": { "pname": "requests", "storePaths": { "out": "x-req" } },
+            "darwin-only": { "excluded": "not available on x86_64-linux" },
+        });
+        let nix = fake_nix(
+            dir.path(),
+            "nix",
+            &format!(
+                r#"
+case "$*" in
+  *--apply*) echo '{entries}' ;;
+  *) echo '{{"source": "/nixpkgs", "system": "x86_64-linux"}}' ;;
+esac
+                "#
+            ),
+        );
+        let jobs = entries
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(attr, entry)| {
+                let attr_path = attr.split('.').collect::<Vec<_>>();
+                serde_json::json!({ "attr": attr, "attrPath": attr_path, "extraValue": entry })
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let nix_eval_jobs = fake_nix(
+            dir.path(),
+            "nix-eval-jobs",
+            &format!("cat <<'EOF'\n{jobs}\nEOF"),
+        );
+
+        let index = |name: &str, extra_args: &[&str]| {
+            let output = dir.path().join(name);
+            let output_arg = output.to_str().unwrap();
+            let args = [
+                ["rippkgs-index", "nixpkgs", "--nix", &nix, "-o", output_arg].as_slice(),
+                extra_args,
+            ]
+            .concat();
+            let Subcmd::Nixpkgs(opts) = Opts::try_parse_from(args).unwrap().cmd else {
+                unreachable!()
+            };
+            super::index(
+                &opts.output,
+                &opts.eval,
+                &opts.filter,
+                super::NixpkgsEvaluator(&opts),
+            )
+            .unwrap();
+
+            let exclusions = rusqlite::Connection::open(&output)
+                .unwrap()
+                .prepare("SELECT attribute, reason FROM excluded ORDER BY attribute")
+                .unwrap()
+                .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+                .unwrap()
+                .collect::<Result<Vec<(String, String)>, _>>()
+                .unwrap();
+            (read_index(&output).0, exclusions)
+        };
+
+        let nix_eval = index("eval.sqlite", &[]);
+        assert_eq!(nix_eval.0.len(), 3);
+        assert!(nix_eval.0["unfree"].unfree);
+        assert_eq!(nix_eval.1.len(), 1);
+        assert_eq!(
+            index(
+                "jobs.sqlite",
+                &["--eval-jobs", "--nix-eval-jobs", &nix_eval_jobs]
+            ),
+            nix_eval
+        );
+
+        // the workers find the entries in nixpkgs as configured, not through `NIXPKGS_ALLOW_*`
+        let args = fake_nix_args(dir.path(), "nix-eval-jobs");
+        assert!(args.contains("import <nixpkgs> arg;"), "{args}");
+        assert!(args.contains("drv: drv.rippkgsEntry"), "{args}");
+    }
+
+    #[test]
+    fn update_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+        let update = |json| {
+            let mut filter = FilterOpts::default().filter().unwrap();
+            let mut evaluator = fake([(None, Ok(registry(json)))]);
+            super::update_index(&output.output, &mut filter, |sink| {
+                crate::eval::evaluate(&EvalOpts::default(), &mut evaluator, sink)
+            })
+            .unwrap()
+        };
+
+        let before = serde_json::json!({
+            "hello": { "version": "2.12" },
+            "vim": { "version": "9.0" },
+            "gone": {},
+        });
+        super::index(
+            &output,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            fake([(None, Ok(registry(before.clone())))]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            update(before),
+            super::UpdateCounts {
+                unchanged: 3,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            update(serde_json::json!({
+                "hello": { "version": "2.12" },
+                "vim": { "version": "9.1" },
+                "new": {},
+            })),
+            super::UpdateCounts {
+                inserted: 1,
+                updated: 1,
+                deleted: 1,
+                unchanged: 1,
+            }
+        );
+
+        let (packages, metadata) = read_index(&output.output);
+        assert_eq!(packages["vim"].version.as_deref(), Some("9.1"));
+        assert!(!packages.contains_key("gone"));
+        assert_eq!(metadata.package_count, 3);
+    }
+
+    #[test]
+    fn update_nix_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+        // `nix search` sorts `legacyPackages` before `packages`
+        let registry = dir.path().join("search.json");
+        std::fs::write(
+            &registry,
+            r#"{"legacyPackages.x86_64-linux.hello": {"pname": "hello", "version": "1"}, "packages.x86_64-linux.hello": {"pname": "hello", "version": "2"}}"#,
+        )
+        .unwrap();
+        let evaluator = || crate::eval::RegistryFile(&registry, crate::formats::Format::Auto);
+
+        super::index(
+            &output,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            evaluator(),
+        )
+        .unwrap();
+
+        let mut filter = FilterOpts::default().filter().unwrap();
+        let counts = super::update_index(&output.output, &mut filter, |sink| {
+            crate::eval::evaluate(&EvalOpts::default(), &mut evaluator(), sink)
+        })
+        .unwrap();
+        assert_eq!(
+            counts,
+            super::UpdateCounts {
+                unchanged: 1,
+                ..Default::default()
+            }
+        );
+
+        let (packages, _) = read_index(&output.output);
+        assert_eq!(packages["hello"].version.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn index_fake_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = OutputOpts {
+            output: dir.path().join("index.sqlite"),
+            update: false,
+        };
+
+        let hello = registry(serde_json::json!({ "hello": {} }));
+        super::index(
+            &output,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            fake([(None, Ok(hello))]),
+        )
+        .unwrap();
+
+        let err = super::index(
+            &output,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            fake([(None, Err(EvalError::NotFound("nix".to_string())))]),
+        )
+        .unwrap_err();
+        assert_eq!(super::exit_code(&err), 3);
+
+        let err = super::index(
+            &output,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            fake([(
+                None,
+                Err(EvalError::Evaluation {
+                    message: "undefined variable 'foo'".to_string(),
+                    trace: vec![],
+                }),
+            )]),
+        )
+        .unwrap_err();
+        assert_eq!(super::exit_code(&err), 4);
+
+        let bad = dir.path().join("bad.json");
+        std::fs::write(&bad, r#"{"hello": {"#).unwrap();
+        let err = super::index(
+            &output,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            RegistryFile(&bad, Format::Auto),
+        )
+        .unwrap_err();
+        assert_eq!(super::exit_code(&err), 5);
+
+        // failed runs leave the previous index in place, in both modes
+        let update = OutputOpts {
+            update: true,
+            ..output
+        };
+        let err = super::index(
+            &update,
+            &EvalOpts::default(),
+            &FilterOpts::default(),
+            RegistryFile(&bad, Format::Auto),
+        )
+        .unwrap_err();
+        assert_eq!(super::exit_code(&err), 5);
+
+        let (packages, _) = read_index(&update.output);
+        assert!(packages.contains_key("hello"));
+    }
+
+    #[test]
+    fn migrate_missing_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join("typo.sqlite");
+
+        let err = super::migrate_index(&crate::MigrateIndex { index }).unwrap_err();
+        assert!(err.to_string().starts_with("no index at"), "{err}");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
 }
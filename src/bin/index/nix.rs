@@ -0,0 +1,604 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Child, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Once,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::progress::{self, Event};
+
+/// The ways running the nix evaluator can fail. Each has its own exit code so automation can tell
+/// them apart.
+#[derive(Debug)]
+pub enum EvalError {
+    /// The evaluator executable, e.g. `nix`, couldn't be found.
+    NotFound(String),
+    /// The evaluator executable couldn't be run.
+    Spawn(std::io::Error),
+    /// Nix reported an evaluation error.
+    Evaluation {
+        /// The final `error:` message reported by nix.
+        message: String,
+        /// The `… while evaluating` trace leading to the error, outermost first.
+        trace: Vec<String>,
+    },
+    /// The evaluator's output wasn't valid registry JSON.
+    Json(serde_json::Error),
+    /// The evaluator ran for longer than the timeout and was killed.
+    Timeout(Duration),
+    /// The evaluator used more memory than the limit, in MiB, and was killed.
+    OutOfMemory(u64),
+    /// The memory use of the evaluator couldn't be measured to enforce the memory limit, so it was
+    /// killed.
+    MemoryUnknown(io::Error),
+}
+
+impl EvalError {
+    pub const fn exit_code(&self) -> u8 {
+        match self {
+            EvalError::NotFound(_) => 3,
+            EvalError::Evaluation { .. } => 4,
+            EvalError::Json(_) => 5,
+            EvalError::Timeout(_) => 6,
+            EvalError::OutOfMemory(_) => 7,
+            EvalError::Spawn(_) | EvalError::MemoryUnknown(_) => 1,
+        }
+    }
+
+    /// Extract the error message and trace from the stderr of a failed `nix` command.
+    pub fn from_stderr(stderr: &str) -> Self {
+        let mut trace = vec![];
+        let mut message = None;
+
+        for line in stderr.lines().map(str::trim) {
+            if let Some(frame) = line.strip_prefix("… ") {
+                trace.push(frame.to_string());
+            } else if let Some(msg) = line.strip_prefix("error:").map(str::trim) {
+                // newer versions of nix print a bare `error:` before the trace
+                if !msg.is_empty() {
+                    message = Some(msg.to_string());
+                }
+            }
+        }
+
+        let message = message
+            .or_else(|| {
+                stderr
+                    .lines()
+                    .map(str::trim)
+                    .rfind(|line| !line.is_empty())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| "unknown error".to_string());
+
+        EvalError::Evaluation { message, trace }
+    }
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::NotFound(program) => write!(f, "`{program}` was not found"),
+            EvalError::Spawn(_) => write!(f, "couldn't run the evaluator"),
+            EvalError::Evaluation { message, trace } => {
+                write!(f, "evaluation failed: {message}")?;
+                for frame in trace {
+                    write!(f, "\n  … {frame}")?;
+                }
+
+                Ok(())
+            }
+            EvalError::Json(_) => write!(f, "invalid registry JSON"),
+            EvalError::Timeout(timeout) => {
+                write!(
+                    f,
+                    "evaluation timed out after {} seconds",
+                    timeout.as_secs()
+                )
+            }
+            EvalError::OutOfMemory(limit) => {
+                write!(f, "evaluation exceeded the memory limit of {limit} MiB")
+            }
+            EvalError::MemoryUnknown(_) => {
+                write!(f, "couldn't measure the memory use of the evaluator")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EvalError::Spawn(err) => Some(err),
+            EvalError::Json(err) => Some(err),
+            EvalError::MemoryUnknown(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Resource limits for an evaluator process. A process that exceeds them is killed along with
+/// everything it started.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub deadline: Option<Deadline>,
+    /// The memory limit in MiB, counting the resident memory of the whole process group.
+    pub max_memory: Option<u64>,
+}
+
+impl Limits {
+    fn enforced(&self) -> bool {
+        self.deadline.is_some() || self.max_memory.is_some()
+    }
+}
+
+/// When evaluation has to be finished by. A deadline can be shared by several evaluator processes,
+/// so that a run as a whole times out.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    pub start: Instant,
+    pub timeout: Duration,
+}
+
+impl Deadline {
+    fn remaining(&self) -> Duration {
+        self.timeout.saturating_sub(self.start.elapsed())
+    }
+}
+
+/// Run `program`, e.g. `nix`, with `args`, returning its stdout. The full stderr is appended to
+/// `error_log` if given.
+pub fn run(
+    program: &str,
+    args: &[&str],
+    error_log: Option<&Path>,
+    limits: Limits,
+) -> Result<Vec<u8>, EvalError> {
+    let mut child = spawn(program, args, limits)?;
+
+    let watchdog = Watchdog::start(&child, limits);
+    let stderr = drain_stderr(&mut child);
+
+    let mut stdout = vec![];
+    let read = child
+        .stdout
+        .take()
+        .expect("stdout is piped")
+        .read_to_end(&mut stdout);
+    let status = wait(&mut child);
+    let limit_error = watchdog.stop();
+
+    read.map_err(EvalError::Spawn)?;
+    let status = status.map_err(EvalError::Spawn)?;
+    let stderr = stderr.join().unwrap_or_default();
+
+    log_stderr(error_log, &stderr);
+
+    if let Some(err) = limit_error {
+        return Err(err);
+    }
+
+    if !status.success() {
+        return Err(EvalError::from_stderr(&String::from_utf8_lossy(&stderr)));
+    }
+
+    Ok(stdout)
+}
+
+/// Run `program`, e.g. `nix`, with `args`, passing its stdout to `read` while it is still running.
+/// The full stderr is appended to `error_log` if given.
+///
+/// If the program fails, its error is returned rather than whatever error `read` ran into on the
+/// truncated output.
+pub fn stream(
+    program: &str,
+    args: &[&str],
+    error_log: Option<&Path>,
+    limits: Limits,
+    read: impl FnOnce(&mut ChildStdout) -> eyre::Result<()>,
+) -> eyre::Result<()> {
+    let mut child = spawn(program, args, limits)?;
+
+    let watchdog = Watchdog::start(&child, limits);
+
+    let stderr = drain_stderr(&mut child);
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let res = read(&mut stdout);
+    if res.is_err() {
+        // nothing more will be read, so don't leave the program running
+        match limits.enforced() {
+            true => kill_group(child.id()),
+            false => {
+                let _ = child.kill();
+            }
+        }
+    }
+    drop(stdout);
+
+    let status = wait(&mut child).map_err(EvalError::Spawn);
+    let limit_error = watchdog.stop();
+
+    let status = status?;
+    let stderr = stderr.join().unwrap_or_default();
+
+    log_stderr(error_log, &stderr);
+
+    if let Some(err) = limit_error {
+        return Err(err.into());
+    }
+
+    // a program that failed by itself (rather than being killed above) explains whatever went wrong
+    // with its output
+    if !status.success() && (res.is_ok() || status.code().is_some()) {
+        return Err(EvalError::from_stderr(&String::from_utf8_lossy(&stderr)).into());
+    }
+
+    res
+}
+
+/// The process group of the running evaluator, if it has its own, so that it can be killed when
+/// rippkgs-index is interrupted. Evaluators are run one at a time.
+static EVALUATOR_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// Spawn `program` with piped stdout and stderr.
+///
+/// To enforce `limits`, the program is put in its own process group so that it can be killed along
+/// with everything it started. Signals sent to the process group of rippkgs-index, e.g. by Ctrl-C,
+/// then no longer reach it, so they are passed on by killing its group.
+fn spawn(program: &str, args: &[&str], limits: Limits) -> Result<Child, EvalError> {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if limits.enforced() {
+        install_signal_handlers();
+        command.process_group(0);
+    }
+
+    let child = command.spawn().map_err(|err| spawn_error(program, err))?;
+    if limits.enforced() {
+        EVALUATOR_GROUP.store(child.id() as i32, Ordering::SeqCst);
+    }
+
+    Ok(child)
+}
+
+/// Wait for `child` to exit, after which its process group no longer needs to be killed.
+fn wait(child: &mut Child) -> io::Result<ExitStatus> {
+    let status = child.wait();
+    let _ =
+        EVALUATOR_GROUP.compare_exchange(child.id() as i32, 0, Ordering::SeqCst, Ordering::SeqCst);
+
+    status
+}
+
+/// Kill the evaluator's process group on SIGINT, SIGTERM and SIGHUP, before dying of the signal as
+/// usual. Signals that are ignored, e.g. under `nohup`, stay ignored.
+fn install_signal_handlers() {
+    static INSTALL: Once = Once::new();
+
+    extern "C" fn kill_evaluator(signal: libc::c_int) {
+        let pgid = EVALUATOR_GROUP.load(Ordering::SeqCst);
+        // SAFETY: killpg, signal and raise are async-signal-safe
+        unsafe {
+            if pgid != 0 {
+                libc::killpg(pgid, libc::SIGKILL);
+            }
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    INSTALL.call_once(|| {
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: the handler only makes async-signal-safe calls
+            unsafe {
+                let handler = kill_evaluator as extern "C" fn(libc::c_int);
+                if libc::signal(signal, handler as libc::sighandler_t) == libc::SIG_IGN {
+                    libc::signal(signal, libc::SIG_IGN);
+                }
+            }
+        }
+    });
+}
+
+/// How often the memory use of a watched process is checked.
+const MEMORY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Kills a process group once it exceeds its [`Limits`].
+struct Watchdog(Option<(mpsc::Sender<()>, JoinHandle<Option<EvalError>>)>);
+
+impl Watchdog {
+    fn start(child: &Child, limits: Limits) -> Self {
+        if !limits.enforced() {
+            return Self(None);
+        }
+
+        let pid = child.id();
+        let (stop, stopped) = mpsc::channel();
+
+        let handle = thread::spawn(move || loop {
+            let mut wait = MEMORY_POLL_INTERVAL;
+            if let Some(deadline) = limits.deadline {
+                wait = wait.min(deadline.remaining());
+            }
+
+            match stopped.recv_timeout(wait) {
+                Err(RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => return None,
+            }
+
+            if let Some(deadline) = limits.deadline.filter(|d| d.remaining().is_zero()) {
+                kill_group(pid);
+                return Some(EvalError::Timeout(deadline.timeout));
+            }
+
+            if let Some(max_memory) = limits.max_memory {
+                match group_memory(pid) {
+                    Ok(memory) if memory > max_memory.saturating_mul(1024) => {
+                        kill_group(pid);
+                        return Some(EvalError::OutOfMemory(max_memory));
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        kill_group(pid);
+                        return Some(EvalError::MemoryUnknown(err));
+                    }
+                }
+            }
+        });
+
+        Self(Some((stop, handle)))
+    }
+
+    /// Stop watching, returning the limit that was exceeded, if any.
+    fn stop(self) -> Option<EvalError> {
+        let (stop, handle) = self.0?;
+        let _ = stop.send(());
+        handle.join().ok().flatten()
+    }
+}
+
+/// The resident memory of the processes in the process group `pgid`, in KiB. This is read from
+/// `/proc` where there is one, and from `ps` otherwise, e.g. on macOS.
+fn group_memory(pgid: u32) -> io::Result<u64> {
+    if !Path::new("/proc/self/stat").exists() {
+        return ps_group_memory(pgid);
+    }
+
+    // SAFETY: sysconf has no memory safety requirements
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    let page_kib = u64::try_from(page_size).unwrap_or(4096) / 1024;
+
+    let mut memory = 0;
+    for entry in fs::read_dir("/proc")? {
+        let path = entry?.path();
+        let is_pid = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()));
+        if !is_pid {
+            continue;
+        }
+
+        // the process may have exited since the directory was read
+        let Ok(stat) = fs::read_to_string(path.join("stat")) else {
+            continue;
+        };
+
+        // the fields after the command name, which may contain spaces, start at `state`, so the
+        // process group is the third and the resident set size in pages the 22nd
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            continue;
+        };
+        let fields = fields.split_whitespace().collect::<Vec<_>>();
+        if fields.get(2).and_then(|f| f.parse::<u32>().ok()) == Some(pgid) {
+            let pages = fields.get(21).and_then(|f| f.parse::<u64>().ok());
+            memory += pages.unwrap_or(0) * page_kib;
+        }
+    }
+
+    Ok(memory)
+}
+
+fn ps_group_memory(pgid: u32) -> io::Result<u64> {
+    let output = Command::new("ps")
+        .args(["-A", "-o", "pgid=,rss="])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("`ps` failed: {}", output.status)));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let group = fields.next()?.parse::<u32>().ok()?;
+            let rss = fields.next()?.parse::<u64>().ok()?;
+            (group == pgid).then_some(rss)
+        })
+        .sum())
+}
+
+/// Kill every process in the process group `pgid`.
+fn kill_group(pgid: u32) {
+    // SAFETY: killpg has no memory safety requirements
+    unsafe {
+        libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+/// Read the stderr of `child` in the background, reporting each line as it arrives so long
+/// evaluations aren't silent, and so the child can't block on a full pipe. Returns the full
+/// stderr once the child closes it.
+fn drain_stderr(child: &mut Child) -> JoinHandle<Vec<u8>> {
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    thread::spawn(move || {
+        let mut buf = vec![];
+        for line in BufReader::new(stderr).split(b'\n') {
+            let Ok(line) = line else {
+                break;
+            };
+
+            progress::report(
+                Event::EvaluatorOutput {
+                    line: &String::from_utf8_lossy(&line),
+                },
+                false,
+            );
+            buf.extend_from_slice(&line);
+            buf.push(b'\n');
+        }
+
+        buf
+    })
+}
+
+fn spawn_error(program: &str, err: std::io::Error) -> EvalError {
+    match err.kind() {
+        ErrorKind::NotFound => EvalError::NotFound(program.to_string()),
+        _ => EvalError::Spawn(err),
+    }
+}
+
+/// Append `stderr` to `error_log`, if given.
+fn log_stderr(error_log: Option<&Path>, stderr: &[u8]) {
+    let Some(error_log) = error_log else {
+        return;
+    };
+
+    // the log is best-effort, and shouldn't hide the result of the evaluation
+    let res = File::options()
+        .append(true)
+        .create(true)
+        .open(error_log)
+        .and_then(|mut f| f.write_all(stderr));
+
+    if let Err(err) = res {
+        eprintln!("couldn't write to {}: {err}", error_log.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use assert_matches::assert_matches;
+
+    use super::{Deadline, EvalError, Limits};
+
+    #[test]
+    fn evaluation_error_with_trace() {
+        let stderr = r#"
+error:
+       … while calling the 'derivationStrict' builtin
+         at /builtin/derivation.nix:9:12: (source not available)
+
+       … while evaluating derivation 'hello-2.12.1'
+         whose name attribute is located at /nix/store/xxx-source/pkgs/stdenv/generic/make-derivation.nix:331:7
+
+       error: undefined variable 'foo'
+       at /nix/store/xxx-source/pkgs/by-name/he/hello/package.nix:10:3:
+"#;
+
+        assert_matches!(
+            EvalError::from_stderr(stderr),
+            EvalError::Evaluation { message, trace }
+                if message == "undefined variable 'foo'"
+                    && trace == [
+                        "while calling the 'derivationStrict' builtin",
+                        "while evaluating derivation 'hello-2.12.1'",
+                    ]
+        );
+    }
+
+    #[test]
+    fn evaluation_error_without_trace() {
+        assert_matches!(
+            EvalError::from_stderr("error: file 'nixpkgs' was not found in the Nix search path\n"),
+            EvalError::Evaluation { message, trace }
+                if message == "file 'nixpkgs' was not found in the Nix search path"
+                    && trace.is_empty()
+        );
+
+        assert_matches!(
+            EvalError::from_stderr("something unexpected\n\n"),
+            EvalError::Evaluation { message, .. } if message == "something unexpected"
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let start = Instant::now();
+        let err = super::run(
+            "sh",
+            &["-c", "sleep 10 & sleep 10; wait"],
+            None,
+            Limits {
+                deadline: Some(Deadline {
+                    start: Instant::now(),
+                    timeout: Duration::from_millis(100),
+                }),
+                ..Limits::default()
+            },
+        )
+        .unwrap_err();
+        assert_matches!(err, EvalError::Timeout(_));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let err = super::stream(
+            "sh",
+            &["-c", "echo '{'; sleep 10"],
+            None,
+            Limits {
+                max_memory: Some(0),
+                ..Limits::default()
+            },
+            |stdout| {
+                std::io::Read::read_to_end(stdout, &mut vec![])?;
+                Ok(())
+            },
+        )
+        .unwrap_err();
+        assert_matches!(err.downcast_ref(), Some(EvalError::OutOfMemory(0)));
+
+        let output = super::run(
+            "echo",
+            &["hello"],
+            None,
+            Limits {
+                deadline: Some(Deadline {
+                    start: Instant::now(),
+                    timeout: Duration::from_secs(10),
+                }),
+                max_memory: Some(1024),
+            },
+        )
+        .unwrap();
+        assert_eq!(output, b"hello\n");
+
+        // a deadline covers every process it is shared by
+        let limits = Limits {
+            deadline: Some(Deadline {
+                start: Instant::now(),
+                timeout: Duration::from_millis(500),
+            }),
+            ..Limits::default()
+        };
+        super::run("sleep", &["0.3"], None, limits).unwrap();
+        let err = super::run("sleep", &["0.3"], None, limits).unwrap_err();
+        assert_matches!(err, EvalError::Timeout(_));
+    }
+}
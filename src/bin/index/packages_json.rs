@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+    time::Instant,
+};
+
+use eyre::{Context, Result};
+use rippkgs::IndexMetadata;
+use serde::{
+    de::{Deserializer, IgnoredAny, MapAccess, Visitor},
+    Deserialize,
+};
+
+use crate::{
+    compression,
+    data::{PackageInfo, PackageMeta},
+    eval::Evaluator,
+    progress::{self, Event},
+    registry::{Entries, Sink},
+};
+
+/// The store path recorded for outputs whose path isn't known, as in `packages.json` and `nix
+/// search` output. Like the `<broken>` paths from `genRegistry`, it never exists in the store.
+pub const UNKNOWN_STORE_PATH: &str = "<unknown>";
+
+/// Reads the `packages.json` published with NixOS channels, either plain or compressed, like the
+/// brotli-compressed `packages.json.br`. The file describes the packages of a single system.
+pub struct PackagesJson<'a>(pub &'a Path);
+
+impl Evaluator for PackagesJson<'_> {
+    fn metadata(&mut self) -> Result<IndexMetadata> {
+        Ok(IndexMetadata {
+            source: Some(self.0.display().to_string()),
+            ..IndexMetadata::new()
+        })
+    }
+
+    fn registry(&mut self, _system: Option<&str>, sink: &mut Sink) -> Result<()> {
+        let f = File::options()
+            .read(true)
+            .open(self.0)
+            .context("opening packages.json")?;
+
+        let start = Instant::now();
+        let reader = compression::decompress(BufReader::new(f), Some(self.0))
+            .context("reading packages.json")?;
+        stream(reader, sink).context("reading packages.json")?;
+
+        progress::report(
+            Event::Finished {
+                step: "read packages.json",
+                seconds: start.elapsed().as_secs_f64(),
+            },
+            true,
+        );
+
+        Ok(())
+    }
+}
+
+/// A package in `packages.json`, as printed by `nix-env -qa --json --meta`.
+#[derive(Debug, Deserialize)]
+pub struct NixEnvPackage {
+    pub name: Option<String>,
+    pub pname: Option<String>,
+    pub version: Option<String>,
+    /// The store path of each output, which channels leave out.
+    #[serde(default)]
+    pub outputs: HashMap<String, Option<String>>,
+    pub meta: Option<PackageMeta>,
+}
+
+impl From<NixEnvPackage> for PackageInfo {
+    fn from(
+        NixEnvPackage {
+            name,
+            pname,
+            version,
+            outputs,
+            meta,
+        }: NixEnvPackage,
+    ) -> Self {
+        // like `builtins.parseDrvName`, for packages without a pname
+        let pname = pname.or_else(|| {
+            let name = name?;
+            let pname = version
+                .as_ref()
+                .and_then(|version| name.strip_suffix(version.as_str())?.strip_suffix('-'))
+                .unwrap_or(&name);
+            Some(pname.to_string())
+        });
+
+        let store_paths = outputs
+            .into_iter()
+            .map(|(output, path)| {
+                let path = path.map_or(UNKNOWN_STORE_PATH.to_string(), |path| {
+                    path.strip_prefix("/nix/store/")
+                        .map_or(path.clone(), str::to_string)
+                });
+                (output, path)
+            })
+            .collect();
+
+        PackageInfo {
+            pname,
+            version,
+            meta,
+            store_paths: Some(store_paths),
+            ..PackageInfo::default()
+        }
+    }
+}
+
+/// Parse a `packages.json` object from `reader`, passing each package to `sink` as soon as it has
+/// been parsed.
+fn stream(reader: impl Read, sink: &mut Sink) -> Result<()> {
+    /// Visits the top-level object, streaming the `packages` in it.
+    struct PackagesJsonVisitor<'e, 's>(&'e mut Entries<'s, NixEnvPackage>);
+
+    impl<'de> Visitor<'de> for PackagesJsonVisitor<'_, '_> {
+        type Value = ();
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a packages.json object")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    "packages" => map.next_value_seed(&mut *self.0)?,
+                    _ => {
+                        map.next_value::<IgnoredAny>()?;
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    let mut sink = |attribute, package: NixEnvPackage| sink(attribute, package.into());
+    let mut entries = Entries::new(&mut sink);
+
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let res = de
+        .deserialize_map(PackagesJsonVisitor(&mut entries))
+        .and_then(|()| de.end());
+
+    entries.finish(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Write};
+    use std::path::Path;
+
+    const PACKAGES: &str = r#"{
+        "version": 2,
+        "packages": {
+            "hello": {
+                "meta": { "description": "A program that produces a familiar, friendly greeting", "homepage": "https://www.gnu.org/software/hello/manual/", "unfree": false },
+                "name": "hello-2.12.1",
+                "outputName": "out",
+                "outputs": { "out": null },
+                "pname": "hello",
+                "system": "x86_64-linux",
+                "version": "2.12.1"
+            },
+            "python3Packages.requests": {
+                "name": "python3.12-requests-2.32.3",
+                "outputs": { "dist": "/nix/store/abc-requests-dist", "out": "/nix/store/def-requests" },
+                "version": "2.32.3"
+            }
+        }
+    }"#;
+
+    fn read(path: &str, contents: &[u8]) -> Vec<(String, super::PackageInfo)> {
+        let reader =
+            crate::compression::decompress(BufReader::new(contents), Some(Path::new(path)))
+                .unwrap();
+
+        let mut packages = vec![];
+        super::stream(reader, &mut |attribute, info| {
+            packages.push((attribute, info));
+            Ok(())
+        })
+        .unwrap();
+        packages
+    }
+
+    #[test]
+    fn packages_json() {
+        let mut compressed = vec![];
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+            writer.write_all(PACKAGES.as_bytes()).unwrap();
+        }
+
+        for packages in [
+            read("packages.json", PACKAGES.as_bytes()),
+            read("packages.json.br", &compressed),
+            read("packages", &compressed),
+        ] {
+            let [(hello, hello_info), (requests, requests_info)] = packages.as_slice() else {
+                panic!("expected two packages, got {packages:?}");
+            };
+
+            assert_eq!(hello, "hello");
+            assert_eq!(hello_info.version.as_deref(), Some("2.12.1"));
+            assert_eq!(
+                hello_info.store_paths.as_ref().unwrap()["out"],
+                super::UNKNOWN_STORE_PATH
+            );
+            assert!(hello_info.meta.as_ref().unwrap().description.is_some());
+
+            assert_eq!(requests, "python3Packages.requests");
+            assert_eq!(requests_info.pname.as_deref(), Some("python3.12-requests"));
+            assert_eq!(
+                requests_info.store_paths.as_ref().unwrap()["dist"],
+                "abc-requests-dist"
+            );
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    io::{stderr, IsTerminal, Write},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// How progress is reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Timing lines on stdout, evaluator output and a status line on stderr.
+    #[default]
+    Human,
+    /// One JSON event per line on stdout.
+    Json,
+}
+
+/// Something that happened while building an index.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A line the evaluator printed to stderr.
+    EvaluatorOutput { line: &'a str },
+    /// Registry entries produced by the evaluator so far.
+    Evaluating {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        system: Option<&'a str>,
+        attributes: u64,
+        scopes: u64,
+    },
+    /// Packages written to the index so far, out of `total` if it is known up front.
+    Writing { inserted: u64, total: Option<u64> },
+    /// Something worth pointing out that doesn't stop the build.
+    Warning { message: &'a str },
+    /// An `--include` or `--exclude` rule left `removed` registry entries out of the index.
+    Filtered { rule: &'a str, removed: u64 },
+    /// A step of the build finished.
+    Finished { step: &'a str, seconds: f64 },
+    /// An existing index was updated in place.
+    Updated {
+        seconds: f64,
+        inserted: u64,
+        updated: u64,
+        deleted: u64,
+        unchanged: u64,
+    },
+}
+
+/// How often `Evaluating` and `Writing` events are reported.
+const INTERVAL: Duration = Duration::from_millis(250);
+
+struct Reporter {
+    mode: ProgressMode,
+    /// When `Evaluating` and `Writing` events were last reported.
+    last_evaluating: Option<Instant>,
+    last_writing: Option<Instant>,
+    /// The number of packages that will be written, if known.
+    total: Option<u64>,
+    /// Whether a status line is shown on stderr and has to be cleared before printing.
+    status: bool,
+}
+
+static REPORTER: OnceLock<Mutex<Reporter>> = OnceLock::new();
+
+fn reporter() -> &'static Mutex<Reporter> {
+    REPORTER.get_or_init(|| {
+        Mutex::new(Reporter {
+            mode: ProgressMode::default(),
+            last_evaluating: None,
+            last_writing: None,
+            total: None,
+            status: false,
+        })
+    })
+}
+
+/// Set how progress is reported for the rest of the run.
+pub fn init(mode: ProgressMode) {
+    reporter().lock().unwrap().mode = mode;
+}
+
+/// Record how many packages will be written, for `Writing` events.
+pub fn set_total(total: u64) {
+    reporter().lock().unwrap().total = Some(total);
+}
+
+/// Report that `inserted` packages have been written so far.
+pub fn writing(inserted: u64, force: bool) {
+    let total = reporter().lock().unwrap().total;
+    report(Event::Writing { inserted, total }, force);
+}
+
+/// Report `event`. Updates on evaluation and writing progress are rate limited unless `force` is
+/// set, so they can be reported for every entry.
+pub fn report(event: Event, force: bool) {
+    let mut reporter = reporter().lock().unwrap();
+
+    let last_update = match event {
+        Event::Evaluating { .. } => Some(&mut reporter.last_evaluating),
+        Event::Writing { .. } => Some(&mut reporter.last_writing),
+        _ => None,
+    };
+
+    if let Some(last_update) = last_update.filter(|_| !force) {
+        if last_update.is_some_and(|last| last.elapsed() < INTERVAL) {
+            return;
+        }
+
+        *last_update = Some(Instant::now());
+    }
+
+    match reporter.mode {
+        ProgressMode::Json => {
+            if let Ok(event) = serde_json::to_string(&event) {
+                println!("{event}");
+            }
+        }
+        ProgressMode::Human => reporter.print(event),
+    }
+}
+
+impl Reporter {
+    fn print(&mut self, event: Event) {
+        let status = match event {
+            Event::EvaluatorOutput { line } => {
+                self.clear_status();
+                eprintln!("{line}");
+                return;
+            }
+            Event::Warning { message } => {
+                self.clear_status();
+                eprintln!("warning: {message}");
+                return;
+            }
+            Event::Evaluating {
+                system,
+                attributes,
+                scopes,
+            } => {
+                let system = system.map(|s| format!(" for {s}")).unwrap_or_default();
+                format!("evaluating{system}: {attributes} attributes in {scopes} scopes")
+            }
+            Event::Writing {
+                inserted,
+                total: Some(total),
+            } => format!("writing: {inserted}/{total} packages"),
+            Event::Writing { inserted, .. } => format!("writing: {inserted} packages"),
+            Event::Filtered { rule, removed } => {
+                self.clear_status();
+                println!("{rule} removed {removed} entries");
+                return;
+            }
+            Event::Finished { step, seconds } => {
+                self.clear_status();
+                println!("{step} in {seconds:.4} seconds");
+                return;
+            }
+            Event::Updated {
+                seconds,
+                inserted,
+                updated,
+                deleted,
+                unchanged,
+            } => {
+                self.clear_status();
+                println!(
+                    "updated index in {seconds:.4} seconds: {inserted} inserted, {updated} \
+                    updated, {deleted} deleted, {unchanged} unchanged",
+                );
+                return;
+            }
+        };
+
+        // a status line would only clutter logs
+        if stderr().is_terminal() {
+            eprint!("\r\x1b[K{status}");
+            let _ = stderr().flush();
+            self.status = true;
+        }
+    }
+
+    fn clear_status(&mut self) {
+        if std::mem::take(&mut self.status) {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Event;
+
+    #[test]
+    fn json_events() {
+        let json = |event: Event| serde_json::to_value(event).unwrap();
+
+        assert_eq!(
+            json(Event::Evaluating {
+                system: None,
+                attributes: 10,
+                scopes: 2,
+            }),
+            serde_json::json!({ "event": "evaluating", "attributes": 10, "scopes": 2 })
+        );
+        assert_eq!(
+            json(Event::Writing {
+                inserted: 5,
+                total: None,
+            }),
+            serde_json::json!({ "event": "writing", "inserted": 5, "total": null })
+        );
+        assert_eq!(
+            json(Event::Filtered {
+                rule: "--exclude hello",
+                removed: 1,
+            }),
+            serde_json::json!({ "event": "filtered", "rule": "--exclude hello", "removed": 1 })
+        );
+        assert_eq!(
+            json(Event::Updated {
+                seconds: 1.5,
+                inserted: 1,
+                updated: 2,
+                deleted: 3,
+                unchanged: 4,
+            }),
+            serde_json::json!({
+                "event": "updated",
+                "seconds": 1.5,
+                "inserted": 1,
+                "updated": 2,
+                "deleted": 3,
+                "unchanged": 4,
+            })
+        );
+    }
+}
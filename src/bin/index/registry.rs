@@ -0,0 +1,184 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+use eyre::Result;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, MapAccess, Visitor};
+
+use crate::{data::PackageInfo, nix::EvalError};
+
+/// Receives registry entries one by one as they are read.
+pub type Sink<'a> = dyn FnMut(String, PackageInfo) -> Result<()> + 'a;
+
+/// Parse a registry JSON object from `reader`, passing each entry to `sink` as soon as it has been
+/// parsed rather than collecting the whole registry first.
+pub fn stream(reader: impl Read, sink: &mut Sink) -> Result<()> {
+    stream_entries(reader, sink)
+}
+
+/// Parse a JSON object of attribute names to `T` from `reader`, passing each entry to `sink` as
+/// soon as it has been parsed.
+pub fn stream_entries<T: DeserializeOwned>(
+    reader: impl Read,
+    sink: &mut dyn FnMut(String, T) -> Result<()>,
+) -> Result<()> {
+    let mut entries = Entries::new(sink);
+
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let res = de.deserialize_map(&mut entries).and_then(|()| de.end());
+
+    entries.finish(res)
+}
+
+/// Deserializes a JSON object of attribute names to `T`, passing each entry to a sink as soon as it
+/// has been parsed. As a [`DeserializeSeed`], it can also read an object nested in a larger
+/// document.
+pub struct Entries<'s, T> {
+    sink: &'s mut dyn FnMut(String, T) -> Result<()>,
+    /// The error returned by the sink, which serde can only carry as a string.
+    sink_error: Option<eyre::Report>,
+}
+
+impl<'s, T> Entries<'s, T> {
+    pub fn new(sink: &'s mut dyn FnMut(String, T) -> Result<()>) -> Self {
+        Self {
+            sink,
+            sink_error: None,
+        }
+    }
+
+    /// The result of reading the document the entries are in, preferring the error the sink
+    /// returned, if any, over the error it caused serde to report.
+    pub fn finish(self, res: Result<(), serde_json::Error>) -> Result<()> {
+        match (res, self.sink_error) {
+            (_, Some(err)) => Err(err),
+            (Err(err), None) => Err(EvalError::Json(err).into()),
+            (Ok(()), None) => Ok(()),
+        }
+    }
+}
+
+impl<'de, T: DeserializeOwned> Visitor<'de> for &mut Entries<'_, T> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of attribute names to package info")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some((attribute, info)) = map.next_entry::<String, T>()? {
+            if let Err(err) = (self.sink)(attribute, info) {
+                self.sink_error = Some(err);
+                return Err(de::Error::custom("stopped reading registry"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'de, T: DeserializeOwned> DeserializeSeed<'de> for &mut Entries<'_, T> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+/// Writes registry entries as a JSON object as they are produced, so a registry can be saved
+/// without collecting it first.
+pub struct Writer<W: Write> {
+    writer: W,
+    empty: bool,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            empty: true,
+        }
+    }
+
+    pub fn write(&mut self, attribute: &str, info: &PackageInfo) -> Result<()> {
+        let sep = if self.empty { b"{" } else { b"," };
+        self.empty = false;
+
+        self.writer.write_all(sep)?;
+        serde_json::to_writer(&mut self.writer, attribute)?;
+        self.writer.write_all(b":")?;
+        serde_json::to_writer(&mut self.writer, info)?;
+
+        Ok(())
+    }
+
+    /// Close the JSON object and flush the underlying writer, returning it.
+    pub fn finish(mut self) -> Result<W> {
+        if self.empty {
+            self.writer.write_all(b"{")?;
+        }
+
+        self.writer.write_all(b"}")?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+
+    use crate::nix::EvalError;
+
+    #[test]
+    fn stream() {
+        let registry = r#"{"a": {"pname": "a"}, "b": {"version": "1"}}"#;
+
+        let mut entries = vec![];
+        super::stream(registry.as_bytes(), &mut |attribute, info| {
+            entries.push((attribute, info));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_matches!(
+            entries.as_slice(),
+            [(a, a_info), (b, b_info)]
+                if a == "a" && a_info.pname.as_deref() == Some("a")
+                    && b == "b" && b_info.version.as_deref() == Some("1")
+        );
+    }
+
+    #[test]
+    fn stream_errors() {
+        let err = super::stream(r#"{"a": {}"#.as_bytes(), &mut |_, _| Ok(())).unwrap_err();
+        assert_matches!(err.downcast_ref::<EvalError>(), Some(EvalError::Json(_)));
+
+        let err = super::stream(r#"{"a": {}, "b": {}}"#.as_bytes(), &mut |attribute, _| {
+            eyre::ensure!(attribute == "a", "unexpected {attribute}");
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "unexpected b");
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        let registry = r#"{"a":{"pname":"a"},"b":{"version":"1"}}"#;
+
+        let mut out = vec![];
+        let mut writer = super::Writer::new(&mut out);
+        super::stream(registry.as_bytes(), &mut |attribute, info| {
+            writer.write(&attribute, &info)
+        })
+        .unwrap();
+        writer.finish().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), registry);
+
+        let mut out = vec![];
+        super::Writer::new(&mut out).finish().unwrap();
+        assert_eq!(out, b"{}");
+    }
+}
@@ -0,0 +1,146 @@
+use clap::Args;
+
+#[derive(Debug, Args)]
+pub struct ScopeOpts {
+    /// How many levels of nested package sets to index. Only package sets that set
+    /// `recurseForDerivations`, like `python3Packages`, are indexed, and `0` indexes top-level
+    /// packages only.
+    #[clap(long, value_name = "LEVELS", default_value_t = 1)]
+    scope_depth: usize,
+
+    /// A package set to index even though it doesn't set `recurseForDerivations`, e.g.
+    /// `python312Packages` or `haskell.packages.ghc96`. May be given several times.
+    #[clap(long = "include-scope", value_name = "ATTRIBUTE", value_parser = attribute_path)]
+    include_scopes: Vec<String>,
+
+    /// A package set to leave out even though it sets `recurseForDerivations`, e.g.
+    /// `haskellPackages`. May be given several times.
+    #[clap(long = "exclude-scope", value_name = "ATTRIBUTE", value_parser = attribute_path)]
+    exclude_scopes: Vec<String>,
+}
+
+impl Default for ScopeOpts {
+    fn default() -> Self {
+        Self {
+            scope_depth: 1,
+            include_scopes: vec![],
+            exclude_scopes: vec![],
+        }
+    }
+}
+
+/// Only accept attribute paths that can be written into nix expressions as they are.
+fn attribute_path(path: &str) -> Result<String, String> {
+    let valid_segment = |segment: &str| {
+        !segment.is_empty()
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_'-+".contains(c))
+    };
+
+    if path.split('.').all(valid_segment) {
+        Ok(path.to_string())
+    } else {
+        Err(format!("`{path}` is not an attribute path"))
+    }
+}
+
+/// A nix list of strings. The strings are attribute paths, so they need no escaping.
+fn nix_list(items: &[String]) -> String {
+    let items = items
+        .iter()
+        .map(|item| format!(r#" "{item}""#))
+        .collect::<String>();
+    format!("[{items} ]")
+}
+
+impl ScopeOpts {
+    /// The `genRegistry` arguments selecting the package sets to index.
+    pub fn gen_registry_args(&self) -> String {
+        format!(
+            "depth = {}; includeScopes = {}; excludeScopes = {};",
+            self.scope_depth,
+            nix_list(&self.include_scopes),
+            nix_list(&self.exclude_scopes),
+        )
+    }
+
+    /// Wrap `pkgs_expr` so that `nix-eval-jobs` descends into the included package sets and not
+    /// into the excluded ones. Included package sets are added at the top level under their full
+    /// attribute path, so they are reached even if their parents aren't.
+    pub fn eval_jobs_expr(&self, pkgs_expr: &str) -> String {
+        format!(
+            r#"
+let pkgs = {pkgs_expr};
+    lib = import <nixpkgs/lib>;
+    included = lib.genAttrs {includes} (name:
+      lib.recurseIntoAttrs (lib.attrByPath (lib.splitString "." name) {{ }} pkgs));
+    excluded = builtins.foldl' lib.recursiveUpdate {{ }} (map (name:
+      lib.setAttrByPath (lib.splitString "." name) {{ recurseForDerivations = false; }})
+      {excludes});
+in lib.recursiveUpdate pkgs excluded // included
+            "#,
+            includes = nix_list(&self.include_scopes),
+            excludes = nix_list(&self.exclude_scopes),
+        )
+    }
+
+    /// Whether a `nix-eval-jobs` job for `attr_path`, evaluated from [`Self::eval_jobs_expr`],
+    /// belongs in the registry `genRegistry` would produce.
+    pub fn indexes(&self, attr_path: &[String]) -> bool {
+        let Some((_, parent)) = attr_path.split_last() else {
+            return false;
+        };
+
+        let path = attr_path.join(".");
+        let segments = path.split('.').collect::<Vec<_>>();
+        let parent_path = segments[..segments.len() - 1].join(".");
+
+        let excluded = self.exclude_scopes.iter().any(|scope| {
+            path.strip_prefix(scope.as_str())
+                .is_some_and(|rest| rest.starts_with('.'))
+        });
+        if excluded {
+            return false;
+        }
+
+        if self.include_scopes.contains(&parent_path) {
+            // nested included package sets are reached through their top-level copy
+            return parent.len() == 1;
+        }
+
+        segments.len() <= self.scope_depth + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScopeOpts;
+
+    #[test]
+    fn eval_jobs_scopes() {
+        let scopes = ScopeOpts {
+            scope_depth: 1,
+            include_scopes: vec![
+                "python312Packages".to_string(),
+                "haskell.packages.ghc96".to_string(),
+            ],
+            exclude_scopes: vec!["haskellPackages".to_string()],
+        };
+        let indexes =
+            |path: &[&str]| scopes.indexes(&path.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+
+        assert!(indexes(&["hello"]));
+        assert!(indexes(&["python3Packages", "requests"]));
+        assert!(!indexes(&["python3Packages", "requests", "dist"]));
+        assert!(indexes(&["python312Packages", "requests"]));
+        assert!(!indexes(&["haskellPackages", "aeson"]));
+        assert!(indexes(&["haskell.packages.ghc96", "aeson"]));
+        assert!(!indexes(&["haskell", "packages", "ghc96", "aeson"]));
+
+        assert_eq!(
+            scopes.gen_registry_args(),
+            r#"depth = 1; includeScopes = [ "python312Packages" "haskell.packages.ghc96" ]; excludeScopes = [ "haskellPackages" ];"#
+        );
+    }
+}
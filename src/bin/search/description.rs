@@ -0,0 +1,120 @@
+use eyre::Context;
+use rusqlite::Connection;
+
+use rippkgs::Package;
+
+use crate::fuzzy;
+
+/// Search the names and descriptions of packages, ranking them by BM25. If `fuzzy_weight` is
+/// given, the fuzzy score used by the default search is added to the ranking with that weight.
+pub fn search(
+    query_str: &str,
+    db: &Connection,
+    num_results: u32,
+    filter_built: bool,
+    system: Option<&str>,
+    fuzzy_weight: Option<f64>,
+) -> eyre::Result<Vec<Package>> {
+    let Some(fts_query) = fts_query(query_str) else {
+        return Ok(vec![]);
+    };
+
+    fuzzy::install_fuzzy_score(db)?;
+
+    // Matches in the attribute or name count the most, and the long description the least. BM25
+    // scores are negative, with better matches further from zero. The fuzzy score is capped so an
+    // exact name match doesn't drown out every description match.
+    let mut query = db
+        .prepare(
+            r#"
+SELECT packages.*,
+    CAST(1000 * (
+        matches.relevance
+        + CASE WHEN ?4 IS NULL THEN 0 ELSE ?4 * MIN(fuzzy_score(?5, packages.attribute, packages.name, packages.aliases), 1000) / 100.0 END
+    ) AS INTEGER) AS score
+FROM (
+    SELECT rowid, -bm25(packages_fts, 4.0, 4.0, 2.0, 1.0) AS relevance
+    FROM packages_fts
+    WHERE packages_fts MATCH ?1
+) AS matches
+JOIN packages ON packages.rowid = matches.rowid
+WHERE ?3 IS NULL OR systems IS NULL OR json_type(systems, '$."' || ?3 || '"') IS NOT NULL
+ORDER BY score DESC
+LIMIT ?2
+            "#,
+        )
+        .context("preparing query")?;
+
+    let rows = query
+        .query_map(
+            rusqlite::params![fts_query, num_results, system, fuzzy_weight, query_str],
+            |r| {
+                let mut package = Package::try_from(r)?;
+                if let Some(system) = system {
+                    package.select_system(system);
+                }
+
+                Ok(package)
+            },
+        )
+        .context("executing query")?;
+
+    fuzzy::installable(rows, num_results, filter_built)
+}
+
+/// An FTS5 query matching any of the words in `query`, or `None` if there are none. Each word is
+/// quoted so that FTS5 syntax in the query is searched for literally.
+fn fts_query(query: &str) -> Option<String> {
+    let words = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+
+    (!words.is_empty()).then(|| words.join(" OR "))
+}
+
+#[cfg(test)]
+mod tests {
+    use rippkgs::Package;
+
+    #[test]
+    fn description_search() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute(Package::create_table(), []).unwrap();
+        db.execute(Package::create_search_table(), []).unwrap();
+
+        for (attribute, description) in [
+            (
+                "pandoc",
+                "Conversion between documentation formats, like markdown to pdf",
+            ),
+            ("wrk", "HTTP benchmarking tool"),
+            ("markdown", "A text-to-HTML conversion tool"),
+            (
+                "hello",
+                "A program that produces a familiar, friendly greeting",
+            ),
+        ] {
+            db.execute(
+                "INSERT INTO packages (attribute, name, description, storePaths) VALUES (?1, ?1, ?2, ?3)",
+                rusqlite::params![attribute, description, r#"{"out": "x"}"#],
+            )
+            .unwrap();
+        }
+        Package::rebuild_search_table(&db).unwrap();
+
+        let search = |query, fuzzy_weight| {
+            super::search(query, &db, 10, false, None, fuzzy_weight)
+                .unwrap()
+                .into_iter()
+                .map(|package| package.attribute)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(search("http benchmarks", None), ["wrk"]);
+        assert_eq!(search("markdown to pdf converter", None)[0], "pandoc");
+        assert_eq!(search("markdown", Some(1.0))[0], "markdown");
+        assert_eq!(search("\"quoted\" NEAR(", None), Vec::<String>::new());
+        assert_eq!(search("  ", None), Vec::<String>::new());
+    }
+}
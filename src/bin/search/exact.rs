@@ -4,7 +4,11 @@ use std::path::PathBuf;
 
 use rippkgs::Package;
 
-pub fn search(query_str: &str, db: &Connection) -> eyre::Result<Option<Package>> {
+pub fn search(
+    query_str: &str,
+    db: &Connection,
+    system: Option<&str>,
+) -> eyre::Result<Option<Package>> {
     let result = db.query_row(
         "SELECT * FROM packages WHERE attribute = ?1",
         rusqlite::params![query_str],
@@ -13,6 +17,14 @@ pub fn search(query_str: &str, db: &Connection) -> eyre::Result<Option<Package>>
 
     match result {
         Ok(mut res) => {
+            if let Some(system) = system {
+                if !res.available_on(system) {
+                    return Ok(None);
+                }
+
+                res.select_system(system);
+            }
+
             let Some(store_paths) = res.store_paths.as_ref() else {
                 // only None when the package is stdenv (not installable) or part of
                 // bootstrapping (should use other attrs). We always filter these out because
@@ -8,36 +8,69 @@ use rusqlite::{functions::FunctionFlags, Connection};
 
 use rippkgs::Package;
 
+/// Search for packages whose attribute, name or aliases fuzzy match `query_str`, ranking each
+/// package by its best match, and shorter attributes first among equal matches. Only packages with
+/// a name containing the characters of the query in order can match, so only those candidates are
+/// scored, and whole rows are only read for the best matches.
 pub fn search(
     query_str: &str,
     db: &Connection,
     num_results: u32,
     filter_built: bool,
+    system: Option<&str>,
 ) -> eyre::Result<Vec<Package>> {
-    db.create_scalar_function(
-        "fuzzy_score",
-        2,
-        FunctionFlags::SQLITE_UTF8,
-        scalar_fuzzy_score,
-    )
-    .context("installing `fuzzy_score` function")?;
+    install_fuzzy_score(db)?;
+    let candidates =
+        Package::fuzzy_candidates(db, query_str).context("finding fuzzy search candidates")?;
 
     let mut query = db
         .prepare(
             r#"
-SELECT *, fuzzy_score(name, ?1) as score
-FROM packages
-ORDER BY score DESC
-LIMIT ?2
+SELECT packages.*, candidates.score
+FROM (
+    SELECT rowid, length(attribute) AS length, fuzzy_score(?1, attribute, name, aliases) AS score
+    FROM packages
+    WHERE rowid IN (SELECT value FROM json_each(?4))
+        AND (?3 IS NULL OR systems IS NULL OR json_type(systems, '$."' || ?3 || '"') IS NOT NULL)
+    ORDER BY score DESC, length
+    LIMIT ?2
+) AS candidates
+JOIN packages ON packages.rowid = candidates.rowid
+ORDER BY candidates.score DESC, candidates.length
             "#,
         )
         .context("preparing query")?;
 
-    let res = query
-        .query_map(rusqlite::params![query_str, num_results], |r| {
-            Package::try_from(r)
-        })
-        .context("executing query")?
+    let rows = query
+        .query_map(
+            rusqlite::params![
+                query_str,
+                num_results,
+                system,
+                serde_json::to_string(&candidates)?
+            ],
+            |r| {
+                let mut package = Package::try_from(r)?;
+                if let Some(system) = system {
+                    package.select_system(system);
+                }
+
+                Ok(package)
+            },
+        )
+        .context("executing query")?;
+
+    installable(rows, num_results, filter_built)
+}
+
+/// Collect up to `num_results` installable packages from `rows`, recording whether they are in the
+/// store, or leaving out those that aren't if `filter_built` is set.
+pub fn installable(
+    rows: impl Iterator<Item = rusqlite::Result<Package>>,
+    num_results: u32,
+    filter_built: bool,
+) -> eyre::Result<Vec<Package>> {
+    let res = rows
         .filter(|package_res| {
             let Ok(package) = package_res else {
                 // carry on the error
@@ -85,17 +118,145 @@ LIMIT ?2
     res
 }
 
+/// Make `fuzzy_score(pattern, attribute, name, aliases)` available to queries on `db`, giving the
+/// best score of `pattern` against any of the names of a package.
+pub fn install_fuzzy_score(db: &Connection) -> eyre::Result<()> {
+    db.create_scalar_function(
+        "fuzzy_score",
+        4,
+        FunctionFlags::SQLITE_UTF8,
+        scalar_fuzzy_score,
+    )
+    .context("installing `fuzzy_score` function")
+}
+
 fn scalar_fuzzy_score(ctx: &FunctionContext) -> rusqlite::Result<i64> {
+    let pattern = ctx.get::<String>(0)?;
+    let attribute = ctx.get::<String>(1)?;
+    let name = ctx.get::<Option<String>>(2)?;
+    let aliases = ctx
+        .get::<Option<String>>(3)?
+        .map(|aliases| serde_json::from_str::<Vec<String>>(&aliases))
+        .transpose()
+        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+        .unwrap_or_default();
+
+    let name_score = name
+        .as_deref()
+        .map_or(0, |name| fuzzy_score(name, &pattern));
+
+    // an attribute named like the package scores the same as its name, unless the pattern has
+    // segments for the package sets too
+    let scored = |attribute: &&String| {
+        pattern.contains('.') || attribute.rsplit('.').next() != name.as_deref()
+    };
+    let own_score = std::iter::once(&attribute)
+        .filter(scored)
+        .map(|attribute| attribute_score(attribute, &pattern));
+    // an alias matching as well as the row's own attribute ranks the row below the aliased one
+    let alias_scores = aliases
+        .iter()
+        .filter(|alias| **alias != attribute)
+        .filter(scored)
+        .map(|alias| attribute_score(alias, &pattern).saturating_sub(1));
+
+    Ok(own_score.chain(alias_scores).fold(name_score, i64::max))
+}
+
+fn fuzzy_score(choice: &str, pattern: &str) -> i64 {
     lazy_static::lazy_static! {
       static ref MATCHER: SkimMatcherV2 = SkimMatcherV2::default().ignore_case();
     }
 
-    let choice = ctx.get::<String>(0)?;
-    let pattern = ctx.get::<String>(1)?;
-
     if choice == pattern {
-        return Ok(i64::MAX);
+        return i64::MAX;
     }
 
-    Ok(MATCHER.fuzzy_match(&choice, &pattern).unwrap_or(0))
+    MATCHER.fuzzy_match(choice, pattern).unwrap_or(0)
+}
+
+/// Score an attribute path segment by segment. The last segment of `pattern` is matched against
+/// the attribute's own name, and each segment before it against one of the package sets the
+/// attribute is in, in order, so `py.req` matches `python3Packages.requests` and `nodejs_20` only
+/// matches attributes named like it.
+fn attribute_score(attribute: &str, pattern: &str) -> i64 {
+    if attribute == pattern {
+        return i64::MAX;
+    }
+
+    let mut segments = attribute.rsplit('.');
+    let mut total = 0i64;
+    for (i, pattern) in pattern.rsplit('.').enumerate() {
+        let score = if i == 0 {
+            segments.next().map_or(0, |name| fuzzy_score(name, pattern))
+        } else {
+            segments
+                .find_map(|segment| Some(fuzzy_score(segment, pattern)).filter(|score| *score > 0))
+                .unwrap_or(0)
+        };
+
+        if score == 0 {
+            return 0;
+        }
+        total = total.saturating_add(score);
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use rippkgs::Package;
+
+    #[test]
+    fn fuzzy_search() {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        db.execute(Package::create_table(), []).unwrap();
+        db.execute(Package::create_candidate_table(), []).unwrap();
+
+        for (attribute, name, out) in [
+            ("ripgrep", "ripgrep", "a"),
+            ("ripgrep-all", "ripgrep-all", "b"),
+            ("grep", "grep", "c"),
+            ("hello", "hello", "d"),
+            ("requests", "requests", "e"),
+            ("python3Packages.requests", "requests", "f"),
+            ("python312Packages.requests", "requests", "f"),
+            ("nodejs", "nodejs", "g"),
+            ("nodejs_20", "nodejs", "g"),
+            ("nodejs_22", "nodejs", "h"),
+        ] {
+            db.execute(
+                "INSERT INTO packages (attribute, name, storePaths) VALUES (?1, ?2, json_object('out', ?3))",
+                rusqlite::params![attribute, name, out],
+            )
+            .unwrap();
+        }
+        Package::rebuild_candidates(&db).unwrap();
+
+        let search = |query| {
+            super::search(query, &db, 10, false, None)
+                .unwrap()
+                .into_iter()
+                .map(|package| package.attribute)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(search("ripgrep")[0], "ripgrep");
+        // names with the characters in another order aren't candidates
+        assert_eq!(search("RG"), ["ripgrep", "ripgrep-all"]);
+        assert_eq!(search("hlo"), ["hello"]);
+        assert_eq!(search("xyz"), Vec::<String>::new());
+
+        assert_eq!(search("requests")[0], "requests");
+        assert_eq!(search("py.req")[0], "python3Packages.requests");
+        assert_eq!(search("nodejs_22")[0], "nodejs_22");
+        // `nodejs` is an alias of `nodejs_20`, and the python scopes alias each other
+        assert_eq!(search("nodejs_20")[..2], ["nodejs_20", "nodejs"]);
+        assert_eq!(search("nodejs")[0], "nodejs");
+        assert_eq!(
+            search("py312.req")[..2],
+            ["python312Packages.requests", "python3Packages.requests"]
+        );
+    }
 }
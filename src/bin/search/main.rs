@@ -1,17 +1,20 @@
+#[allow(dead_code)]
 mod data;
+mod description;
 mod exact;
 mod fuzzy;
+mod why_missing;
 
 use std::fmt::Display;
 use std::io::stdout;
 use std::path::PathBuf;
 
 use clap::builder::{PathBufValueParser, TypedValueParser};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use comfy_table::TableComponent;
 use eyre::Context;
 use eyre::Result;
-use rippkgs::Package;
+use rippkgs::{IndexMetadata, Package};
 use rusqlite::OpenFlags;
 use xdg::BaseDirectories;
 
@@ -48,9 +51,13 @@ impl TypedValueParser for IndexPathValueParser {
 }
 
 #[derive(Debug, Parser)]
+#[command(subcommand_negates_reqs = true)]
 struct Opts {
+    #[command(subcommand)]
+    cmd: Option<Subcmd>,
+
     /// The location of the rippkgs index to use.
-    #[arg(short, long, default_value_t = get_default_index_path(), value_parser = IndexPathValueParser::default())]
+    #[arg(short, long, global = true, default_value_t = get_default_index_path(), value_parser = IndexPathValueParser::default())]
     index: IndexPath,
 
     /// The maximum number of results to return.
@@ -64,18 +71,48 @@ struct Opts {
     #[arg(long)]
     exact: bool,
 
+    /// Search the names and descriptions of packages for the words of the query, ranking results
+    /// by relevance instead of fuzzy matching names.
+    #[arg(short, long, conflicts_with = "exact")]
+    description: bool,
+
+    /// Add the fuzzy score to the ranking of a description search, with this weight relative to
+    /// the description relevance, e.g. `--blend-fuzzy 1`.
+    #[arg(long, value_name = "WEIGHT", requires = "description")]
+    blend_fuzzy: Option<f64>,
+
     /// Filter results by whether the /nix/store path already exists.
     ///
     /// Only applies when doing fuzzy matching.
     #[arg(long)]
     filter_built: bool,
 
-    /// Print the results as json.
+    /// Only return packages available on this system, using its store paths.
+    ///
+    /// Defaults to the current system when the index records availability for it.
     #[arg(long)]
+    system: Option<String>,
+
+    /// Print the results as json.
+    #[arg(long, global = true)]
     json: bool,
 
-    /// The search query.
-    query: String,
+    /// The search query, matched against the name, attribute and aliases of packages. Dots match
+    /// attribute paths segment by segment, e.g. `py.req`. Use `--` before the query to search for
+    /// the name of a subcommand.
+    #[arg(required = true)]
+    query: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Subcmd {
+    /// Print information about how the index was generated
+    Info,
+    /// Explain why an attribute doesn't show up in search results
+    WhyMissing {
+        /// The attribute path, e.g. `python3Packages.requests`.
+        attribute: String,
+    },
 }
 
 fn get_default_index_path() -> IndexPath {
@@ -95,17 +132,54 @@ fn main() -> Result<()> {
     )
     .context("reading index")?;
 
+    check_schema_version(&conn)?;
+
+    let system = resolve_system(&conn, opts.system)?;
+
+    match opts.cmd {
+        Some(Subcmd::Info) => return print_info(&conn, opts.json),
+        Some(Subcmd::WhyMissing { attribute }) => {
+            let explanation = why_missing::explain(&attribute, &conn, system.as_deref())
+                .context("explaining missing attribute")?;
+
+            if opts.json {
+                serde_json::to_writer(stdout(), &explanation).context("printing explanation")?;
+            } else {
+                println!("{attribute}: {explanation}");
+            }
+
+            return Ok(());
+        }
+        None => (),
+    }
+
+    // clap requires the query when no subcommand is given
+    let query = opts.query.unwrap_or_default();
+
     let results: Box<dyn Iterator<Item = Package>> = if opts.exact {
-        let result =
-            exact::search(opts.query.as_str(), &conn).context("searching for exact query")?;
+        let result = exact::search(query.as_str(), &conn, system.as_deref())
+            .context("searching for exact query")?;
 
         Box::new(result.into_iter())
+    } else if opts.description {
+        let results = description::search(
+            query.as_str(),
+            &conn,
+            opts.max_results,
+            opts.filter_built,
+            system.as_deref(),
+            opts.blend_fuzzy,
+        )
+        .context("searching descriptions")?;
+
+        Box::new(results.into_iter())
     } else {
         let results = fuzzy::search(
-            opts.query.as_str(),
+            query.as_str(),
             &conn,
             opts.max_results,
             opts.filter_built,
+            system.as_deref(),
         )
         .context("searching for fuzzy query")?;
 
@@ -143,3 +217,126 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn check_schema_version(conn: &rusqlite::Connection) -> Result<()> {
+    let version = rippkgs::schema_version(conn).context("reading index schema version")?;
+
+    if version < rippkgs::SCHEMA_VERSION {
+        eyre::bail!(
+            "the index has schema version {version}, but this version of rippkgs requires schema \
+            version {}. Regenerate the index, or upgrade it with `rippkgs-index migrate`.",
+            rippkgs::SCHEMA_VERSION,
+        );
+    } else if version > rippkgs::SCHEMA_VERSION {
+        eyre::bail!(
+            "the index has schema version {version}, which is newer than the version supported by \
+            this version of rippkgs ({}). Please upgrade rippkgs.",
+            rippkgs::SCHEMA_VERSION,
+        );
+    }
+
+    Ok(())
+}
+
+/// Pick the system to filter results by, if the index records per-system availability.
+fn resolve_system(
+    conn: &rusqlite::Connection,
+    requested: Option<String>,
+) -> Result<Option<String>> {
+    let IndexMetadata {
+        system, systems, ..
+    } = IndexMetadata::read(conn).context("reading index metadata")?;
+
+    match (systems, requested) {
+        (Some(systems), Some(requested)) => {
+            if !systems.contains(&requested) {
+                eyre::bail!(
+                    "the index doesn't include {requested}, it was generated for {}",
+                    systems.join(", ")
+                );
+            }
+
+            Ok(Some(requested))
+        }
+        (Some(systems), None) => {
+            let host = host_system();
+            Ok(systems.contains(&host).then_some(host))
+        }
+        (None, Some(requested)) => {
+            if let Some(system) = system.filter(|system| *system != requested) {
+                eyre::bail!(
+                    "the index was only generated for {system}. Regenerate it with \
+                    `rippkgs-index nixpkgs --system {requested}` to search {requested}."
+                );
+            }
+
+            Ok(None)
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// The nix system string for the system rippkgs was built for, e.g. `aarch64-darwin`.
+fn host_system() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+
+    format!("{}-{os}", std::env::consts::ARCH)
+}
+
+fn print_info(conn: &rusqlite::Connection, json: bool) -> Result<()> {
+    let metadata = IndexMetadata::read(conn).context("reading index metadata")?;
+
+    if json {
+        serde_json::to_writer(stdout(), &metadata).context("printing metadata")?;
+        return Ok(());
+    }
+
+    let IndexMetadata {
+        rippkgs_version,
+        schema_version,
+        created_at,
+        source,
+        revision,
+        nixpkgs_arg,
+        overlays,
+        system,
+        systems,
+        package_count,
+    } = metadata;
+
+    let mut table = comfy_table::Table::new();
+    table
+        .load_preset(comfy_table::presets::NOTHING)
+        .add_row(vec!["rippkgs version".to_string(), rippkgs_version])
+        .add_row(vec![
+            "schema version".to_string(),
+            schema_version.to_string(),
+        ])
+        .add_row(vec![
+            "created at".to_string(),
+            created_at.unwrap_or_default(),
+        ])
+        .add_row(vec!["source".to_string(), source.unwrap_or_default()])
+        .add_row(vec!["revision".to_string(), revision.unwrap_or_default()])
+        .add_row(vec![
+            "nixpkgs arg".to_string(),
+            nixpkgs_arg.unwrap_or_default(),
+        ])
+        .add_row(vec![
+            "overlays".to_string(),
+            overlays.unwrap_or_default().join(", "),
+        ])
+        .add_row(vec!["system".to_string(), system.unwrap_or_default()])
+        .add_row(vec![
+            "indexed systems".to_string(),
+            systems.unwrap_or_default().join(", "),
+        ])
+        .add_row(vec!["packages".to_string(), package_count.to_string()]);
+
+    println!("{table}");
+
+    Ok(())
+}
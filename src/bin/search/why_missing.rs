@@ -0,0 +1,87 @@
+use eyre::{Context, Result};
+use rippkgs::{Exclusion, Package};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+
+/// Why an attribute does or doesn't show up in search results.
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Explanation {
+    /// The package is in the index and can be searched for.
+    Indexed,
+    /// The package is in the index, but not available on the system being searched.
+    NotAvailable {
+        system: String,
+        systems: Vec<String>,
+    },
+    /// The package is in the index, but has no `out` output, so search leaves it out.
+    NotInstallable,
+    /// The attribute was left out of the index when it was generated.
+    Excluded { reason: String },
+    /// The attribute isn't in the indexed package set, or is in a scope that isn't indexed.
+    Unknown,
+}
+
+pub fn explain(attribute: &str, db: &Connection, system: Option<&str>) -> Result<Explanation> {
+    let package = db
+        .query_row(
+            "SELECT * FROM packages WHERE attribute = ?1",
+            rusqlite::params![attribute],
+            |r| Package::try_from(r),
+        )
+        .optional()
+        .context("looking up package")?;
+
+    let Some(package) = package else {
+        let exclusion = Exclusion::find(db, attribute).context("looking up exclusion")?;
+
+        return Ok(match exclusion {
+            Some(Exclusion { reason, .. }) => Explanation::Excluded { reason },
+            None => Explanation::Unknown,
+        });
+    };
+
+    if let Some(system) = system.filter(|system| !package.available_on(system)) {
+        return Ok(Explanation::NotAvailable {
+            system: system.to_string(),
+            systems: package
+                .systems
+                .map(|systems| systems.into_keys().collect())
+                .unwrap_or_default(),
+        });
+    }
+
+    let out = package
+        .store_paths
+        .as_ref()
+        .and_then(|store_paths| store_paths.get("out"));
+    if out.is_none() {
+        return Ok(Explanation::NotInstallable);
+    }
+
+    Ok(Explanation::Indexed)
+}
+
+impl std::fmt::Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Explanation::Indexed => write!(f, "it is in the index, and should show up in searches"),
+            Explanation::NotAvailable { system, systems } => write!(
+                f,
+                "it isn't available on {system}, only on {}",
+                systems.join(", ")
+            ),
+            Explanation::NotInstallable => {
+                write!(f, "it has no `out` output, so it isn't installable")
+            }
+            Explanation::Excluded { reason } => {
+                write!(f, "it was left out of the index: {reason}")
+            }
+            Explanation::Unknown => write!(
+                f,
+                "it isn't an attribute of the indexed package set, or is inside a package set \
+                that isn't indexed"
+            ),
+        }
+    }
+}
@@ -1,7 +1,8 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use rusqlite::{types::Type, OptionalExtension};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Package {
     pub attribute: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -19,6 +20,22 @@ pub struct Package {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub long_description: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<serde_json::Value>,
+    #[serde(default)]
+    pub broken: bool,
+    #[serde(default)]
+    pub insecure: bool,
+    #[serde(default)]
+    pub unfree: bool,
+    #[serde(default)]
+    pub unsupported: bool,
+    /// The store paths of the package for each system it is available on, when the index was
+    /// generated for several systems.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub systems: Option<BTreeMap<String, HashMap<String, String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub score: Option<i64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -37,10 +54,474 @@ CREATE TABLE packages (
     propagatedNativeBuildInputs TEXT,
     description TEXT,
     long_description TEXT,
+    homepage TEXT,
+    license TEXT,
+    broken INTEGER NOT NULL DEFAULT 0,
+    insecure INTEGER NOT NULL DEFAULT 0,
+    unfree INTEGER NOT NULL DEFAULT 0,
+    unsupported INTEGER NOT NULL DEFAULT 0,
+    systems TEXT,
+    aliases TEXT,
     PRIMARY KEY (attribute)
 )
         "#
     }
+
+    /// The table holding the names fuzzy search looks up candidates in, which is filled in by
+    /// [`Package::rebuild_candidates`]. Its one row is a blob of the lowercase names of every
+    /// package, each followed by a nul byte and the package's rowid as 8 big-endian bytes, sorted
+    /// so the names are compact enough to be scanned for every query.
+    pub const fn create_candidate_table() -> &'static str {
+        r#"
+CREATE TABLE fuzzy_candidates (
+    names BLOB NOT NULL
+)
+        "#
+    }
+
+    /// Record the attributes of packages with the same `out` path as `aliases`, like `nodejs` and
+    /// `nodejs_20`, and rebuild the fuzzy search candidates from the names of every package. This
+    /// has to be done whenever packages are written. Only rows whose aliases change are rewritten.
+    pub fn rebuild_candidates(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        // `<broken>` and `<unknown>` paths don't identify a package
+        conn.execute(
+            r#"
+UPDATE packages SET aliases = aliased.attributes
+FROM (
+    SELECT packages.rowid AS id, groups.attributes
+    FROM packages
+    LEFT JOIN (
+        SELECT json_extract(storePaths, '$.out') AS out, json_group_array(attribute) AS attributes
+        FROM (SELECT attribute, storePaths FROM packages ORDER BY attribute)
+        WHERE json_extract(storePaths, '$.out') NOT IN ('<broken>', '<unknown>')
+        GROUP BY out
+        HAVING COUNT(*) > 1
+    ) AS groups ON json_extract(packages.storePaths, '$.out') = groups.out
+) AS aliased
+WHERE packages.rowid = aliased.id AND packages.aliases IS NOT aliased.attributes
+            "#,
+            [],
+        )?;
+
+        let mut keys = vec![];
+        let mut query = conn.prepare("SELECT rowid, attribute, name, aliases FROM packages")?;
+        let mut rows = query.query([])?;
+        while let Some(row) = rows.next()? {
+            let rowid = row.get::<_, i64>(0)?;
+            let attribute = row.get::<_, String>(1)?;
+            let name = row.get::<_, Option<String>>(2)?;
+            let aliases = row
+                .get::<_, Option<String>>(3)?
+                .map(|aliases| serde_json::from_str::<Vec<String>>(&aliases))
+                .transpose()
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, Type::Text, e.into()))?
+                .unwrap_or_default();
+
+            for name in fuzzy_names(&attribute, name.as_deref(), &aliases) {
+                let mut key = name.to_lowercase().into_bytes();
+                key.push(0);
+                key.extend(rowid.to_be_bytes());
+                keys.push(key);
+            }
+        }
+        keys.sort_unstable();
+        keys.dedup();
+
+        conn.execute("DELETE FROM fuzzy_candidates", [])?;
+        conn.execute(
+            "INSERT INTO fuzzy_candidates (names) VALUES (?1)",
+            [keys.concat()],
+        )?;
+
+        Ok(())
+    }
+
+    /// The rowids of the packages with a name containing the characters of the last segment of
+    /// `query` in order, ignoring case. Only these can fuzzy match `query`.
+    pub fn fuzzy_candidates(
+        conn: &rusqlite::Connection,
+        query: &str,
+    ) -> rusqlite::Result<Vec<i64>> {
+        let names = conn
+            .query_row("SELECT names FROM fuzzy_candidates", [], |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .optional()?
+            .unwrap_or_default();
+        let malformed = || {
+            rusqlite::Error::FromSqlConversionFailure(
+                0,
+                Type::Blob,
+                "malformed fuzzy search candidates".into(),
+            )
+        };
+
+        let query = query.rsplit('.').next().unwrap_or_default().to_lowercase();
+        let mut rowids = vec![];
+        let mut rest = names.as_slice();
+        // packages with the same name are next to each other, so each name is only matched once
+        let mut previous = None;
+        while !rest.is_empty() {
+            let end = rest.iter().position(|b| *b == 0).ok_or_else(malformed)?;
+            let (name, tail) = rest.split_at(end);
+            let rowid = tail.get(1..9).ok_or_else(malformed)?;
+            rest = &tail[9..];
+
+            let matches = match previous {
+                Some((previous, matches)) if previous == name => matches,
+                _ => is_subsequence(query.as_bytes(), name),
+            };
+            previous = Some((name, matches));
+
+            if matches {
+                rowids.push(i64::from_be_bytes(rowid.try_into().expect("8 bytes")));
+            }
+        }
+        rowids.sort_unstable();
+        rowids.dedup();
+
+        Ok(rowids)
+    }
+
+    /// The full-text search table over the attribute, name and descriptions of the packages. It
+    /// reads its contents from the `packages` table, so it has to be rebuilt with
+    /// [`Package::rebuild_search_table`] whenever packages are written.
+    pub const fn create_search_table() -> &'static str {
+        r#"
+CREATE VIRTUAL TABLE packages_fts USING fts5(
+    attribute,
+    name,
+    description,
+    long_description,
+    content = 'packages',
+    tokenize = 'porter unicode61'
+)
+        "#
+    }
+
+    /// Rebuild the full-text search table from the `packages` table.
+    pub fn rebuild_search_table(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO packages_fts (packages_fts) VALUES ('rebuild')",
+            [],
+        )
+        .map(|_| ())
+    }
+
+    /// Remove the package at `attribute` from the full-text search table, before its row is
+    /// replaced or deleted.
+    pub fn remove_from_search_table(
+        conn: &rusqlite::Connection,
+        attribute: &str,
+    ) -> rusqlite::Result<()> {
+        conn.prepare_cached(
+            r#"
+INSERT INTO packages_fts (packages_fts, rowid, attribute, name, description, long_description)
+SELECT 'delete', rowid, attribute, name, description, long_description
+FROM packages
+WHERE attribute = ?1
+            "#,
+        )?
+        .execute([attribute])
+        .map(|_| ())
+    }
+
+    /// Add the package at `attribute` to the full-text search table, after its row is written.
+    pub fn add_to_search_table(
+        conn: &rusqlite::Connection,
+        attribute: &str,
+    ) -> rusqlite::Result<()> {
+        conn.prepare_cached(
+            r#"
+INSERT INTO packages_fts (rowid, attribute, name, description, long_description)
+SELECT rowid, attribute, name, description, long_description
+FROM packages
+WHERE attribute = ?1
+            "#,
+        )?
+        .execute([attribute])
+        .map(|_| ())
+    }
+
+    /// Whether the package is available on `system`. Packages from indexes without per-system
+    /// information are assumed to be available.
+    pub fn available_on(&self, system: &str) -> bool {
+        self.systems
+            .as_ref()
+            .is_none_or(|systems| systems.contains_key(system))
+    }
+
+    /// Use the store paths for `system` as the package's store paths.
+    pub fn select_system(&mut self, system: &str) {
+        if let Some(store_paths) = self
+            .systems
+            .as_ref()
+            .and_then(|systems| systems.get(system))
+        {
+            self.store_paths = Some(store_paths.clone());
+        }
+    }
+}
+
+/// Each entry upgrades an index from the schema version matching its position to the next one.
+/// Migrations must never be edited once released; add a new entry instead, and update the
+/// `create_table` functions to match.
+const MIGRATIONS: &[&str] = &[
+    // 0 -> 1: package meta and the metadata table
+    r#"
+ALTER TABLE packages ADD COLUMN homepage TEXT;
+ALTER TABLE packages ADD COLUMN license TEXT;
+ALTER TABLE packages ADD COLUMN broken INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE packages ADD COLUMN insecure INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE packages ADD COLUMN unfree INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE packages ADD COLUMN unsupported INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE metadata (
+    key TEXT NOT NULL,
+    value,
+    PRIMARY KEY (key)
+);
+
+INSERT INTO metadata (key, value)
+SELECT 'package_count', COUNT(*) FROM packages;
+    "#,
+    // 1 -> 2: per-system store paths
+    r#"
+ALTER TABLE packages ADD COLUMN systems TEXT;
+    "#,
+    // 2 -> 3: attributes left out of the index
+    r#"
+CREATE TABLE excluded (
+    attribute TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    PRIMARY KEY (attribute)
+);
+    "#,
+    // 3 -> 4: full-text search over names and descriptions
+    r#"
+CREATE VIRTUAL TABLE packages_fts USING fts5(
+    attribute,
+    name,
+    description,
+    long_description,
+    content = 'packages',
+    tokenize = 'porter unicode61'
+);
+
+INSERT INTO packages_fts (packages_fts) VALUES ('rebuild');
+    "#,
+    // 4 -> 5: fuzzy search on attributes, names and aliases, filled in by
+    // `Package::rebuild_candidates`
+    r#"
+ALTER TABLE packages ADD COLUMN aliases TEXT;
+
+CREATE TABLE fuzzy_candidates (
+    names BLOB NOT NULL
+);
+    "#,
+];
+
+/// The version of the index schema written by this version of rippkgs. This is stored in the
+/// index as `PRAGMA user_version`.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Read the schema version of an index.
+pub fn schema_version(conn: &rusqlite::Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Mark an index as having the current schema version.
+pub fn set_schema_version(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.pragma_update(None, "user_version", SCHEMA_VERSION)
+}
+
+/// The names of a package that fuzzy search matches against: its name and the last segment of its
+/// attribute and aliases. The package sets an attribute is in are left out, since they would make
+/// nearly every package in them a candidate.
+fn fuzzy_names<'a>(
+    attribute: &'a str,
+    name: Option<&'a str>,
+    aliases: &'a [String],
+) -> impl Iterator<Item = &'a str> {
+    std::iter::once(attribute)
+        .chain(aliases.iter().map(String::as_str))
+        .filter_map(|attribute| attribute.rsplit('.').next())
+        .chain(name)
+        .filter(|name| !name.is_empty())
+}
+
+/// Whether `haystack` contains the bytes of `needle` in order.
+fn is_subsequence(needle: &[u8], haystack: &[u8]) -> bool {
+    let mut haystack = haystack.iter();
+    needle.iter().all(|b| haystack.any(|c| c == b))
+}
+
+/// Upgrade an index to the current schema version in place, returning the version it was upgraded
+/// from. Indexes with a newer schema version than [`SCHEMA_VERSION`] are left untouched.
+pub fn migrate(conn: &mut rusqlite::Connection) -> rusqlite::Result<u32> {
+    let tx = conn.transaction()?;
+
+    let from = schema_version(&tx)?;
+    if from >= SCHEMA_VERSION {
+        return Ok(from);
+    }
+
+    for migration in &MIGRATIONS[from as usize..] {
+        tx.execute_batch(migration)?;
+    }
+    Package::rebuild_candidates(&tx)?;
+
+    set_schema_version(&tx)?;
+    tx.commit()?;
+
+    Ok(from)
+}
+
+/// An attribute that was left out of the index, and why.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exclusion {
+    pub attribute: String,
+    pub reason: String,
+}
+
+impl Exclusion {
+    pub const fn create_table() -> &'static str {
+        r#"
+CREATE TABLE excluded (
+    attribute TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    PRIMARY KEY (attribute)
+)
+        "#
+    }
+
+    /// Look up why `attribute` was left out of the index, if it was.
+    pub fn find(conn: &rusqlite::Connection, attribute: &str) -> rusqlite::Result<Option<Self>> {
+        conn.query_row(
+            "SELECT attribute, reason FROM excluded WHERE attribute = ?1",
+            [attribute],
+            |row| {
+                Ok(Self {
+                    attribute: row.get("attribute")?,
+                    reason: row.get("reason")?,
+                })
+            },
+        )
+        .optional()
+    }
+}
+
+/// Information about how an index was generated, stored as key/value rows in the `metadata`
+/// table.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    /// The version of rippkgs-index that wrote the index.
+    pub rippkgs_version: String,
+    /// The version of the index schema, read from `PRAGMA user_version`.
+    pub schema_version: u32,
+    /// When the index was written, as an ISO 8601 UTC timestamp.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+    /// The nixpkgs path or registry file the index was generated from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The git revision of the source, when it could be detected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// The expression passed as the `config` argument to `import <nixpkgs>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nixpkgs_arg: Option<String>,
+    /// The overlay files applied to nixpkgs, in order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overlays: Option<Vec<String>>,
+    /// The value of `builtins.currentSystem` during evaluation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// The systems whose availability and store paths are recorded for each package.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub systems: Option<Vec<String>>,
+    /// The number of packages written to the index.
+    pub package_count: u64,
+}
+
+impl IndexMetadata {
+    pub const fn create_table() -> &'static str {
+        r#"
+CREATE TABLE metadata (
+    key TEXT NOT NULL,
+    value, -- no type affinity, so values are read back with the type they were written with
+    PRIMARY KEY (key)
+)
+        "#
+    }
+
+    /// Metadata for an index written by this version of rippkgs.
+    pub fn new() -> Self {
+        Self {
+            rippkgs_version: env!("CARGO_PKG_VERSION").to_string(),
+            schema_version: SCHEMA_VERSION,
+            ..Default::default()
+        }
+    }
+
+    /// Write the metadata into the `metadata` table. If `created_at` is unset, the current time is
+    /// used.
+    pub fn write(&self, conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        let mut insert = conn.prepare(
+            r#"
+INSERT OR REPLACE INTO metadata (key, value)
+VALUES (?1, ?2)
+            "#,
+        )?;
+
+        insert.execute(rusqlite::params!["rippkgs_version", self.rippkgs_version])?;
+        insert.execute(rusqlite::params!["source", self.source])?;
+        insert.execute(rusqlite::params!["revision", self.revision])?;
+        insert.execute(rusqlite::params!["nixpkgs_arg", self.nixpkgs_arg])?;
+        insert.execute(rusqlite::params!["overlays", json_value(&self.overlays)?])?;
+        insert.execute(rusqlite::params!["system", self.system])?;
+        insert.execute(rusqlite::params!["systems", json_value(&self.systems)?])?;
+        insert.execute(rusqlite::params!["package_count", self.package_count])?;
+
+        conn.execute(
+            r#"
+INSERT OR REPLACE INTO metadata (key, value)
+VALUES ('created_at', COALESCE(?1, strftime('%Y-%m-%dT%H:%M:%SZ', 'now')))
+            "#,
+            rusqlite::params![self.created_at],
+        )?;
+
+        Ok(())
+    }
+
+    /// Read the metadata from the `metadata` table.
+    pub fn read(conn: &rusqlite::Connection) -> rusqlite::Result<Self> {
+        let mut query = conn.prepare("SELECT key, value FROM metadata")?;
+        let mut rows = query.query([])?;
+
+        let mut metadata = Self {
+            schema_version: schema_version(conn)?,
+            ..Default::default()
+        };
+        while let Some(row) = rows.next()? {
+            let key: String = row.get("key")?;
+            match key.as_str() {
+                "rippkgs_version" => metadata.rippkgs_version = row.get("value")?,
+                "created_at" => metadata.created_at = row.get("value")?,
+                "source" => metadata.source = row.get("value")?,
+                "revision" => metadata.revision = row.get("value")?,
+                "nixpkgs_arg" => metadata.nixpkgs_arg = row.get("value")?,
+                "overlays" => metadata.overlays = json_column(row, "value")?,
+                "system" => metadata.system = row.get("value")?,
+                "systems" => metadata.systems = json_column(row, "value")?,
+                "package_count" => metadata.package_count = row.get("value")?,
+                // keys written by newer versions of rippkgs-index
+                _ => (),
+            }
+        }
+
+        Ok(metadata)
+    }
 }
 
 impl<'r, 'd> TryFrom<&'r rusqlite::Row<'d>> for Package {
@@ -48,58 +529,44 @@ impl<'r, 'd> TryFrom<&'r rusqlite::Row<'d>> for Package {
 
     fn try_from(row: &'r rusqlite::Row<'d>) -> Result<Self, Self::Error> {
         let attribute: String = row.get("attribute")?;
-        let store_paths: Option<String> = row.get("storePaths")?;
-        let propagated_build_inputs: Option<String> = row.get("propagatedBuildInputs")?;
-        let propagated_native_build_inputs: Option<String> =
-            row.get("propagatedNativeBuildInputs")?;
+        let store_paths = json_column(row, "storePaths")?;
+        let propagated_build_inputs = json_column(row, "propagatedBuildInputs")?;
+        let propagated_native_build_inputs = json_column(row, "propagatedNativeBuildInputs")?;
         let name: Option<String> = row.get("name")?;
         let version: Option<String> = row.get("version")?;
         let description: Option<String> = row.get("description")?;
         let long_description: Option<String> = row.get("long_description")?;
+        let homepage = json_column(row, "homepage")?;
+        let license = json_column(row, "license")?;
+        let broken: bool = row.get("broken")?;
+        let insecure: bool = row.get("insecure")?;
+        let unfree: bool = row.get("unfree")?;
+        let unsupported: bool = row.get("unsupported")?;
+        let systems = json_column(row, "systems")?;
 
         let score = if cfg!(debug_assertions) {
-            row.get("score")?
+            // only fuzzy queries select a score
+            match row.get("score") {
+                Err(rusqlite::Error::InvalidColumnName(_)) => None,
+                score => score?,
+            }
         } else {
             None
         };
 
-        let store_paths = store_paths
-            .map(|s| serde_json::from_str(&s))
-            .transpose()
-            .map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    0,
-                    rusqlite::types::Type::Text,
-                    Box::new(e),
-                )
-            })?;
-        let propagated_build_inputs = propagated_build_inputs
-            .map(|s| serde_json::from_str(&s))
-            .transpose()
-            .map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    0,
-                    rusqlite::types::Type::Text,
-                    Box::new(e),
-                )
-            })?;
-        let propagated_native_build_inputs = propagated_native_build_inputs
-            .map(|s| serde_json::from_str(&s))
-            .transpose()
-            .map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    0,
-                    rusqlite::types::Type::Text,
-                    Box::new(e),
-                )
-            })?;
-
         Ok(Package {
             attribute,
             name,
             version,
             description,
             long_description,
+            homepage,
+            license,
+            broken,
+            insecure,
+            unfree,
+            unsupported,
+            systems,
             store_paths,
             propagated_build_inputs,
             propagated_native_build_inputs,
@@ -108,3 +575,193 @@ impl<'r, 'd> TryFrom<&'r rusqlite::Row<'d>> for Package {
         })
     }
 }
+
+/// Encode a value for a nullable TEXT column holding JSON.
+fn json_value<T: Serialize>(value: &Option<T>) -> rusqlite::Result<Option<String>> {
+    value
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Read a nullable TEXT column holding a JSON-encoded value.
+fn json_column<T: DeserializeOwned>(
+    row: &rusqlite::Row,
+    column: &str,
+) -> rusqlite::Result<Option<T>> {
+    let value: Option<String> = row.get(column)?;
+
+    value
+        .map(|s| serde_json::from_str(&s))
+        .transpose()
+        .map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                row.as_ref().column_index(column).unwrap_or_default(),
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    fn indexes(conn: &rusqlite::Connection) -> Vec<String> {
+        conn.prepare("SELECT name FROM pragma_index_list('packages') ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn columns(conn: &rusqlite::Connection, table: &str) -> Vec<(String, String, bool)> {
+        conn.prepare(&format!("PRAGMA table_info({table})"))
+            .unwrap()
+            .query_map([], |row| Ok((row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn migrations_match_create_table() {
+        let fresh = rusqlite::Connection::open_in_memory().unwrap();
+        fresh.execute(super::Package::create_table(), []).unwrap();
+        fresh
+            .execute(super::IndexMetadata::create_table(), [])
+            .unwrap();
+        fresh.execute(super::Exclusion::create_table(), []).unwrap();
+        fresh
+            .execute(super::Package::create_search_table(), [])
+            .unwrap();
+        fresh
+            .execute(super::Package::create_candidate_table(), [])
+            .unwrap();
+
+        let mut migrated = rusqlite::Connection::open_in_memory().unwrap();
+        migrated
+            .execute(
+                r#"
+CREATE TABLE packages (
+    attribute TEXT NOT NULL,
+    name TEXT,
+    version TEXT,
+    storePaths TEXT,
+    propagatedBuildInputs TEXT,
+    propagatedNativeBuildInputs TEXT,
+    description TEXT,
+    long_description TEXT,
+    PRIMARY KEY (attribute)
+)
+                "#,
+                [],
+            )
+            .unwrap();
+
+        assert_eq!(super::migrate(&mut migrated).unwrap(), 0);
+        assert_eq!(
+            super::schema_version(&migrated).unwrap(),
+            super::SCHEMA_VERSION
+        );
+
+        for table in [
+            "packages",
+            "metadata",
+            "excluded",
+            "packages_fts",
+            "fuzzy_candidates",
+        ] {
+            assert_eq!(columns(&fresh, table), columns(&migrated, table), "{table}");
+        }
+        assert_eq!(indexes(&fresh), indexes(&migrated));
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(super::IndexMetadata::create_table(), [])
+            .unwrap();
+        super::set_schema_version(&conn).unwrap();
+
+        let metadata = super::IndexMetadata {
+            created_at: Some("2025-01-01T00:00:00Z".to_string()),
+            source: Some("/nix/store/nixpkgs".to_string()),
+            revision: Some("abc123".to_string()),
+            nixpkgs_arg: Some("{ }".to_string()),
+            overlays: Some(vec!["a.nix".to_string(), "b.nix".to_string()]),
+            system: Some("x86_64-linux".to_string()),
+            systems: Some(vec!["x86_64-linux".to_string()]),
+            package_count: 42,
+            ..super::IndexMetadata::new()
+        };
+        metadata.write(&conn).unwrap();
+        assert_eq!(
+            serde_json::to_value(super::IndexMetadata::read(&conn).unwrap()).unwrap(),
+            serde_json::to_value(&metadata).unwrap()
+        );
+
+        // unset fields stay unset, except for the creation time
+        super::IndexMetadata::new().write(&conn).unwrap();
+        let read = super::IndexMetadata::read(&conn).unwrap();
+        assert_eq!(read.source, None);
+        assert_eq!(read.overlays, None);
+        assert_eq!(read.package_count, 0);
+        assert!(read.created_at.is_some());
+    }
+
+    #[test]
+    fn fuzzy_candidates() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute(super::Package::create_table(), []).unwrap();
+        conn.execute(super::Package::create_candidate_table(), [])
+            .unwrap();
+        for (attribute, name, out) in [
+            ("ripgrep", "RipGrep", "a"),
+            ("python3Packages.requests", "python3.12-requests", "b"),
+            ("python312Packages.requests", "python3.12-requests", "b"),
+            ("hello", "hello", "c"),
+        ] {
+            conn.execute(
+                "INSERT INTO packages (attribute, name, storePaths) VALUES (?1, ?2, json_object('out', ?3))",
+                [attribute, name, out],
+            )
+            .unwrap();
+        }
+        super::Package::rebuild_candidates(&conn).unwrap();
+
+        let candidates = |query| {
+            let rowids = super::Package::fuzzy_candidates(&conn, query).unwrap();
+            rowids
+                .into_iter()
+                .map(|rowid| {
+                    conn.query_row(
+                        "SELECT attribute FROM packages WHERE rowid = ?1",
+                        [rowid],
+                        |row| row.get::<_, String>(0),
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(candidates("RGP"), ["ripgrep"]);
+        assert_eq!(candidates("gri"), Vec::<String>::new());
+        // only the last segment of the query has to be in a single name
+        assert_eq!(
+            candidates("py312.req"),
+            ["python3Packages.requests", "python312Packages.requests"]
+        );
+        assert_eq!(candidates("xyz"), Vec::<String>::new());
+        assert_eq!(candidates("").len(), 4);
+
+        // aliases are names too
+        conn.execute(
+            "UPDATE packages SET storePaths = json_object('out', 'a') WHERE attribute = 'hello'",
+            [],
+        )
+        .unwrap();
+        super::Package::rebuild_candidates(&conn).unwrap();
+        assert_eq!(candidates("rg"), ["ripgrep", "hello"]);
+    }
+}